@@ -0,0 +1,57 @@
+//! Times draws for server-wide parties. Run with `cargo bench`.
+use std::time::{Duration, Instant};
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+// The bot is a binary, so the engine is pulled in directly. Only the draw is used here, and
+// clippy builds the module's tests without a harness to run them.
+#[path = "../src/matching.rs"]
+#[allow(dead_code, unused_imports)]
+mod matching;
+
+const PARTICIPANTS: u64 = 10_000;
+const RUNS: u64 = 20;
+/// Anything slower than this means the draw stopped scaling linearly
+const BUDGET: Duration = Duration::from_millis(100);
+
+fn bench(name: &str, rules: &matching::Rules, gifts: usize) {
+    let participants = (1..=PARTICIPANTS).collect::<Vec<_>>();
+    let mut slowest = Duration::ZERO;
+    let mut total = Duration::ZERO;
+    for seed in 0..RUNS {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        let started = Instant::now();
+        let assignment = matching::draw(&participants, rules, &mut rng).unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(assignment.pairs().len(), participants.len() * gifts);
+        slowest = slowest.max(elapsed);
+        total += elapsed;
+    }
+    println!(
+        "{name}: {PARTICIPANTS} participants, mean {:?}, slowest {slowest:?}",
+        total / RUNS as u32
+    );
+    assert!(
+        slowest < BUDGET,
+        "{name} took {slowest:?}, over the {BUDGET:?} budget"
+    );
+}
+
+fn main() {
+    bench("plain", &matching::Rules::default(), 1);
+
+    let mut rules = matching::Rules::default();
+    for uid in (1..PARTICIPANTS).step_by(2) {
+        rules.exclude(uid, uid + 1);
+    }
+    bench("couples excluded", &rules, 1);
+
+    let mut rules = matching::Rules::default();
+    rules.require_single_cycle();
+    bench("single loop", &rules, 1);
+
+    let mut rules = matching::Rules::default();
+    rules.set_gifts_per_person(3);
+    bench("three gifts each", &rules, 3);
+}
@@ -0,0 +1,86 @@
+use sha2::{Digest, Sha256};
+
+/// A draw's pairs in a stable text form: `giver>receiver`, sorted and separated by `;`
+pub fn canonical(pairs: impl IntoIterator<Item = (u64, u64)>) -> String {
+    let mut pairs = pairs.into_iter().collect::<Vec<_>>();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(giver, receiver)| format!("{giver}>{receiver}"))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Reads pairs written by [`canonical`]
+pub fn parse_pairs(record: &str) -> Option<Vec<(u64, u64)>> {
+    record
+        .split(';')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (giver, receiver) = pair.split_once('>')?;
+            Some((giver.parse().ok()?, receiver.parse().ok()?))
+        })
+        .collect()
+}
+
+/// The file published at the reveal: the input the draw ran on, followed by a `record` line
+/// with the pairs it made. Together with the seed it is all a replay needs.
+pub fn sealed_text(input: &str, record: &str) -> String {
+    format!("{input}record {record}\n")
+}
+
+/// Splits a file written by [`sealed_text`] back into its input and record
+pub fn split_sealed(text: &str) -> Option<(&str, &str)> {
+    let body = text.strip_suffix('\n').unwrap_or(text);
+    let (input, record) = match body.rsplit_once('\n') {
+        Some((input, last)) => (&text[..input.len() + 1], last),
+        None => ("", body),
+    };
+    Some((input, record.strip_prefix("record ")?))
+}
+
+/// The hash published when a party is drawn. It ties the bot to the seed and the sealed text
+/// before anyone gets to see either, and anyone can recompute it as `sha256("{seed}:{text}")`
+pub fn commitment(seed: u64, text: &str) -> String {
+    Sha256::digest(format!("{seed}:{text}"))
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_do_not_depend_on_row_order() {
+        assert_eq!(canonical([(2, 1), (1, 2)]), "1>2;2>1");
+        assert_eq!(canonical([(1, 2), (2, 1)]), canonical([(2, 1), (1, 2)]));
+        assert_eq!(parse_pairs("1>2;2>1"), Some(vec![(1, 2), (2, 1)]));
+        assert_eq!(parse_pairs(""), Some(vec![]));
+        assert_eq!(parse_pairs("1>2;2"), None);
+    }
+
+    #[test]
+    fn sealed_texts_split_back_into_input_and_record() {
+        let text = sealed_text("gifts 1\nperson 1\nperson 2\n", "1>2;2>1");
+        assert_eq!(
+            split_sealed(&text),
+            Some(("gifts 1\nperson 1\nperson 2\n", "1>2;2>1"))
+        );
+        assert_eq!(split_sealed("record 1>2;2>1"), Some(("", "1>2;2>1")));
+        assert_eq!(split_sealed("gifts 1\n"), None);
+    }
+
+    #[test]
+    fn commitments_change_with_seed_and_record() {
+        let commitment = commitment(7, "1>2;2>1");
+        assert_eq!(commitment.len(), 64);
+        assert_eq!(
+            commitment,
+            super::commitment(7, &canonical([(2, 1), (1, 2)]))
+        );
+        assert_ne!(commitment, super::commitment(8, "1>2;2>1"));
+        assert_ne!(commitment, super::commitment(7, "1>2;2>3;3>1"));
+    }
+}
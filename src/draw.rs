@@ -0,0 +1,1405 @@
+use std::collections::{BTreeMap, HashMap};
+
+use async_sqlite::rusqlite::{Connection, Result, params};
+use color_eyre::eyre::eyre;
+use rand::Rng;
+use tracing::{Level, event};
+
+use crate::{
+    PartyRecord, audit, elephant,
+    lifecycle::{self, PartyState},
+    matching,
+};
+
+pub struct Participant {
+    pub uid: u64,
+    pub name: String,
+    pub hint: String,
+    pub team_id: Option<u64>,
+    pub region: Option<String>,
+    pub ships_abroad: bool,
+    /// Spending limit in dollars, for parties with budget tiers
+    pub budget: Option<u32>,
+    /// Comma separated interest tags
+    pub interests: Option<String>,
+}
+
+/// One of a party's assignments
+pub struct MatchRow {
+    pub giver_id: u64,
+    pub receiver_id: u64,
+    pub receiver_name: String,
+    pub receiver_hint: String,
+    pub loop_id: usize,
+    pub loop_position: usize,
+    pub receiver_team: Option<String>,
+}
+
+/// Everything a party's draw depends on
+pub struct DrawInput {
+    pub party: PartyRecord,
+    pub participants: Vec<Participant>,
+    pub exclusions: Vec<(u64, u64)>,
+    /// Pairs from earlier rounds of the party, then from earlier parties in the follow-up chain,
+    /// most recent first
+    pub history: Vec<Vec<(u64, u64)>>,
+    pub teams: Vec<(u64, String)>,
+    /// Giver and receiver pairs the admin fixed ahead of the draw
+    pub pins: Vec<(u64, u64)>,
+}
+
+impl DrawInput {
+    pub fn load(dbc: &Connection, party: PartyRecord) -> Result<Self> {
+        let mut query = dbc.prepare(
+            "SELECT uid, name, hint, team_id, region, ships_abroad, budget, interests
+                FROM participants WHERE party_id = ?1",
+        )?;
+        let mut participants = query
+            .query_map([&party.id], |row| {
+                Ok(Participant {
+                    uid: row.get::<_, u64>("uid")?,
+                    name: row.get::<_, String>("name")?,
+                    hint: row.get::<_, String>("hint")?,
+                    team_id: row.get::<_, Option<u64>>("team_id")?,
+                    region: row.get::<_, Option<String>>("region")?,
+                    ships_abroad: row.get::<_, bool>("ships_abroad")?,
+                    budget: row.get::<_, Option<u32>>("budget")?,
+                    interests: row.get::<_, Option<String>>("interests")?,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        participants.sort_by_key(|user| user.uid);
+        let exclusions = dbc
+            .prepare("SELECT user_a, user_b FROM party_exclusions WHERE party_id = ?1 ORDER BY user_a, user_b")?
+            .query_map([&party.id], |row| {
+                Ok((row.get::<_, u64>("user_a")?, row.get::<_, u64>("user_b")?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        let teams = party_teams(dbc, &party.id)?;
+        let pins = dbc
+            .prepare(
+                "SELECT giver_id, receiver_id FROM party_pins WHERE party_id = ?1
+                    ORDER BY giver_id, receiver_id",
+            )?
+            .query_map([&party.id], |row| {
+                Ok((
+                    row.get::<_, u64>("giver_id")?,
+                    row.get::<_, u64>("receiver_id")?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        let mut rounds = BTreeMap::<u32, Vec<(u64, u64)>>::new();
+        for pair in dbc
+            .prepare("SELECT round, giver_id, receiver_id FROM party_rounds WHERE party_id = ?1")?
+            .query_map([&party.id], |row| {
+                Ok((
+                    row.get::<_, u32>("round")?,
+                    row.get::<_, u64>("giver_id")?,
+                    row.get::<_, u64>("receiver_id")?,
+                ))
+            })?
+        {
+            let (round, giver, receiver) = pair?;
+            rounds.entry(round).or_default().push((giver, receiver));
+        }
+        let mut history = rounds.into_values().rev().collect::<Vec<_>>();
+        let mut previous = party.follows_id.clone();
+        for _ in 0..party.history_depth {
+            let Some(previous_id) = previous else {
+                break;
+            };
+            let pairs = current_pairs(dbc, &previous_id)?;
+            if !pairs.is_empty() {
+                history.push(pairs);
+            }
+            previous = dbc.query_one(
+                "SELECT follows_id FROM parties WHERE id = ?1",
+                [previous_id],
+                |row| row.get::<_, Option<String>>("follows_id"),
+            )?;
+        }
+        Ok(DrawInput {
+            party,
+            participants,
+            exclusions,
+            history,
+            teams,
+            pins,
+        })
+    }
+
+    /// Everything [`DrawInput::rules`] reads, one fact per line, so the draw can be replayed from
+    /// this text alone. Names and hints only end up in the rows, so they are left out.
+    pub fn dump(&self) -> String {
+        let mut lines = vec![
+            format!("single_loop {}", self.party.single_loop),
+            format!("gifts {}", self.party.gifts_per_person),
+            format!("regional {}", self.party.regional),
+            format!("budget_tiers {}", self.party.budget_tiers),
+        ];
+        for (team_id, team_name) in &self.teams {
+            lines.push(format!("team {team_id} {team_name}"));
+        }
+        for user in &self.participants {
+            let uid = user.uid;
+            lines.push(format!("person {uid}"));
+            if let Some(team_id) = user.team_id {
+                lines.push(format!("team_of {uid} {team_id}"));
+            }
+            if let Some(region) = &user.region {
+                lines.push(format!("region {uid} {} {region}", user.ships_abroad));
+            }
+            if let Some(budget) = user.budget {
+                lines.push(format!("budget {uid} {budget}"));
+            }
+            if let Some(interests) = &user.interests {
+                lines.push(format!("interests {uid} {interests}"));
+            }
+        }
+        for (a, b) in &self.exclusions {
+            lines.push(format!("exclude {a} {b}"));
+        }
+        for (giver, receiver) in &self.pins {
+            lines.push(format!("pin {giver} {receiver}"));
+        }
+        for pairs in &self.history {
+            lines.push(format!(
+                "history {}",
+                audit::canonical(pairs.iter().copied())
+            ));
+        }
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+
+    /// Reads a [`DrawInput::dump`] back. The party only gets the settings the draw depends on,
+    /// and everyone's name and hint are left empty.
+    pub fn parse(text: &str) -> color_eyre::Result<Self> {
+        let mut input = DrawInput {
+            party: PartyRecord {
+                id: String::new(),
+                admin_id: 0,
+                party_name: String::new(),
+                ends_at: 0,
+                follows_id: None,
+                history_depth: 0,
+                single_loop: false,
+                gifts_per_person: 1,
+                channel_id: None,
+                regional: false,
+                budget_tiers: false,
+                white_elephant: false,
+                steal_limit: 0,
+                rounds: 1,
+                current_round: 1,
+                min_participants: 0,
+                state: PartyState::Drawn,
+                ship_by: None,
+            },
+            participants: Vec::new(),
+            exclusions: Vec::new(),
+            history: Vec::new(),
+            teams: Vec::new(),
+            pins: Vec::new(),
+        };
+        for (number, line) in text.lines().enumerate() {
+            input.read_line(line).ok_or_else(|| {
+                eyre!(
+                    "Line {} of the draw input can't be read: {line}",
+                    number + 1
+                )
+            })?;
+        }
+        Ok(input)
+    }
+
+    fn read_line(&mut self, line: &str) -> Option<()> {
+        let (key, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match key {
+            "single_loop" => self.party.single_loop = rest.parse().ok()?,
+            "gifts" => self.party.gifts_per_person = rest.parse().ok()?,
+            "regional" => self.party.regional = rest.parse().ok()?,
+            "budget_tiers" => self.party.budget_tiers = rest.parse().ok()?,
+            "team" => {
+                let (team_id, team_name) = rest.split_once(' ')?;
+                self.teams
+                    .push((team_id.parse().ok()?, team_name.to_owned()));
+            }
+            "person" => self.participants.push(Participant {
+                uid: rest.parse().ok()?,
+                name: String::new(),
+                hint: String::new(),
+                team_id: None,
+                region: None,
+                ships_abroad: false,
+                budget: None,
+                interests: None,
+            }),
+            "team_of" | "region" | "budget" | "interests" => {
+                let (uid, value) = rest.split_once(' ')?;
+                let uid = uid.parse::<u64>().ok()?;
+                let user = self.participants.iter_mut().find(|user| user.uid == uid)?;
+                match key {
+                    "team_of" => user.team_id = Some(value.parse().ok()?),
+                    "region" => {
+                        let (ships_abroad, region) = value.split_once(' ')?;
+                        user.ships_abroad = ships_abroad.parse().ok()?;
+                        user.region = Some(region.to_owned());
+                    }
+                    "budget" => user.budget = Some(value.parse().ok()?),
+                    _ => user.interests = Some(value.to_owned()),
+                }
+            }
+            "exclude" | "pin" => {
+                let (a, b) = rest.split_once(' ')?;
+                let pair = (a.parse().ok()?, b.parse().ok()?);
+                if key == "exclude" {
+                    self.exclusions.push(pair);
+                } else {
+                    self.pins.push(pair);
+                }
+            }
+            "history" => self.history.push(audit::parse_pairs(rest)?),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Groups participants by the id the matching engine draws over: their own uid,
+    /// or their team's id when the party is played team-vs-team
+    fn members(&self) -> HashMap<u64, Vec<&Participant>> {
+        let mut members = HashMap::<u64, Vec<&Participant>>::new();
+        for participant in &self.participants {
+            let id = if self.teams.is_empty() {
+                participant.uid
+            } else if let Some(team_id) = participant.team_id {
+                team_id
+            } else {
+                event!(
+                    Level::WARN,
+                    "{} joined team party {} without a team",
+                    participant.uid,
+                    self.party.id
+                );
+                continue;
+            };
+            members.entry(id).or_default().push(participant);
+        }
+        members
+    }
+
+    /// How many ids the draw needs and how many it has, when it has too few to be made at all.
+    /// Team parties draw over teams, and everyone needs someone different for each of their gifts.
+    pub fn shortfall(&self) -> Option<(usize, usize)> {
+        let needed = self.party.gifts_per_person as usize + 1;
+        let got = self.members().len();
+        (got < needed).then_some((needed, got))
+    }
+
+    /// The ids to draw over and the rules the draw has to honor
+    pub fn rules(&self) -> (Vec<u64>, matching::Rules) {
+        let members = self.members();
+        let mut ids = members.keys().copied().collect::<Vec<_>>();
+        ids.sort();
+        let mut rules = matching::Rules::default();
+        if self.party.single_loop {
+            rules.require_single_cycle();
+        }
+        rules.set_gifts_per_person(self.party.gifts_per_person as usize);
+        if self.teams.is_empty() {
+            for &(a, b) in &self.exclusions {
+                rules.exclude(a, b);
+            }
+            for pairs in &self.history {
+                rules.avoid_repeats(pairs.iter().copied());
+            }
+            for user in &self.participants {
+                if let Some(interests) = &user.interests {
+                    rules.set_interests(user.uid, interests.split(','));
+                }
+            }
+            if self.party.budget_tiers {
+                for user in &self.participants {
+                    if let Some(budget) = user.budget {
+                        rules.set_tier(user.uid, budget);
+                    }
+                }
+            }
+            if self.party.regional {
+                for user in &self.participants {
+                    if let Some(region) = &user.region {
+                        rules.set_region(user.uid, region, user.ships_abroad);
+                    }
+                }
+            }
+            // Someone who left after being pinned would make the draw impossible
+            for &(giver, receiver) in &self.pins {
+                if members.contains_key(&giver) && members.contains_key(&receiver) {
+                    rules.pin(giver, receiver);
+                }
+            }
+        } else {
+            // Teams change between parties, so only exclusions carry over to team draws
+            let team_of = self
+                .participants
+                .iter()
+                .filter_map(|user| Some((user.uid, user.team_id?)))
+                .collect::<HashMap<_, _>>();
+            for (a, b) in &self.exclusions {
+                if let (Some(&team_a), Some(&team_b)) = (team_of.get(a), team_of.get(b))
+                    && team_a != team_b
+                {
+                    rules.exclude(team_a, team_b);
+                }
+            }
+        }
+        (ids, rules)
+    }
+
+    /// Expands an assignment over [`DrawInput::rules`] ids into rows for every giving member
+    pub fn rows(&self, assignment: &matching::Assignment) -> Vec<MatchRow> {
+        let members = self.members();
+        let team_names = self.teams.iter().cloned().collect::<HashMap<_, _>>();
+        let positions = loop_positions(assignment);
+        let mut rows = Vec::new();
+        for pair in assignment.pairs() {
+            let (loop_id, loop_position) = positions.get(&pair.giver).copied().unwrap_or_default();
+            for giver in &members[&pair.giver] {
+                for receiver in &members[&pair.receiver] {
+                    rows.push(MatchRow {
+                        giver_id: giver.uid,
+                        receiver_id: receiver.uid,
+                        receiver_name: receiver.name.clone(),
+                        receiver_hint: receiver.hint.clone(),
+                        loop_id,
+                        loop_position,
+                        receiver_team: team_names.get(&pair.receiver).cloned(),
+                    });
+                }
+            }
+        }
+        rows
+    }
+}
+
+/// Each giver's loop and place in it, which lets the reveal walk the gift chain in order.
+/// Parties with several gifts per person have no loops and keep the defaults.
+fn loop_positions(assignment: &matching::Assignment) -> HashMap<u64, (usize, usize)> {
+    assignment
+        .cycles()
+        .into_iter()
+        .enumerate()
+        .flat_map(|(loop_id, givers)| {
+            givers
+                .into_iter()
+                .enumerate()
+                .map(move |(loop_position, giver)| (giver, (loop_id, loop_position)))
+        })
+        .collect()
+}
+
+/// What happened to a party when someone left it
+#[derive(Debug, PartialEq, Eq)]
+pub enum Departure {
+    NotJoined,
+    /// Left before the draw, or from a team that still has other members
+    Removed,
+    /// These givers inherited the leaver's receivers
+    Spliced(Vec<u64>),
+    /// Only a full redraw can work around the leaver
+    Stuck(matching::MatchError),
+}
+
+/// Takes `leaver` out of a party. Once the party is drawn, their givers inherit their receivers
+/// so nobody else's target changes. When that can't be done they stay in, unless `force` is set
+/// and the party is left for a redraw.
+pub fn leave(
+    dbc: &mut Connection,
+    party: PartyRecord,
+    leaver: u64,
+    force: bool,
+) -> Result<Departure> {
+    let tx = dbc.transaction()?;
+    let input = DrawInput::load(&tx, party)?;
+    let Some(participant) = input.participants.iter().find(|user| user.uid == leaver) else {
+        return Ok(Departure::NotJoined);
+    };
+    let party_id = &input.party.id;
+    let drawn = is_drawn(&tx, party_id)?;
+    let departure = if !drawn {
+        Departure::Removed
+    } else if !input.teams.is_empty() {
+        // The team keeps its giver and receiver as long as someone else is still in it
+        let teammates = input
+            .participants
+            .iter()
+            .filter(|user| user.uid != leaver && user.team_id == participant.team_id)
+            .count();
+        if teammates == 0 {
+            Departure::Stuck(matching::MatchError::CannotSplice(leaver))
+        } else {
+            Departure::Removed
+        }
+    } else {
+        let pairs = current_pairs(&tx, party_id)?
+            .into_iter()
+            .map(|(giver, receiver)| matching::Pair { giver, receiver })
+            .collect::<Vec<_>>();
+        let (_, rules) = input.rules();
+        match matching::splice(&pairs, leaver, &rules) {
+            Ok(spliced) => {
+                Departure::Spliced(splice_into(&tx, party_id, &pairs, &spliced, leaver)?)
+            }
+            Err(e) => Departure::Stuck(e),
+        }
+    };
+    if matches!(departure, Departure::Stuck(_)) && !force {
+        return Ok(departure);
+    }
+    if drawn {
+        tx.execute(
+            "DELETE FROM assignments WHERE party_id = ?1 AND (giver_id = ?2 OR receiver_id = ?2)",
+            params![party_id, leaver],
+        )?;
+    }
+    tx.execute(
+        "DELETE FROM participants WHERE party_id = ?1 AND uid = ?2",
+        params![party_id, leaver],
+    )?;
+    tx.execute(
+        "DELETE FROM party_pins WHERE party_id = ?1 AND (giver_id = ?2 OR receiver_id = ?2)",
+        params![party_id, leaver],
+    )?;
+    tx.commit()?;
+    Ok(departure)
+}
+
+/// Adds the pairs `spliced` gained over `pairs` to the party's assignments and renumbers the
+/// loops. Returns the givers of the new pairs.
+fn splice_into(
+    dbc: &Connection,
+    party_id: &str,
+    pairs: &[matching::Pair],
+    spliced: &matching::Assignment,
+    leaver: u64,
+) -> Result<Vec<u64>> {
+    let mut givers = Vec::new();
+    for pair in spliced.pairs() {
+        if pairs.contains(pair) {
+            continue;
+        }
+        // The leaver's own row already has everything their receiver wrote at signup
+        dbc.execute(
+            "INSERT INTO assignments
+                (party_id, giver_id, receiver_id, receiver_name, receiver_hint, receiver_team)
+            SELECT party_id, ?2, receiver_id, receiver_name, receiver_hint, receiver_team
+                FROM assignments WHERE party_id = ?1 AND giver_id = ?3 AND receiver_id = ?4",
+            params![party_id, pair.giver, leaver, pair.receiver],
+        )?;
+        givers.push(pair.giver);
+    }
+    for (giver, (loop_id, loop_position)) in loop_positions(spliced) {
+        set_loop(dbc, party_id, giver, loop_id, loop_position)?;
+    }
+    givers.sort();
+    givers.dedup();
+    Ok(givers)
+}
+
+/// What happened to a party when someone joined it
+#[derive(Debug, PartialEq, Eq)]
+pub enum Arrival {
+    /// The party hasn't been drawn yet, so the draw will include them
+    Joined,
+    /// These givers now give to the newcomer
+    Inserted(Vec<u64>),
+    /// Only a full redraw could fit the newcomer in, so they were not added
+    Stuck(matching::MatchError),
+    /// The party's white elephant game has started without a turn or a gift for them,
+    /// so they were not added
+    GameStarted,
+}
+
+/// Signs `newcomer` up for a party. Once the party is drawn they take over a few existing pairs,
+/// so nobody but the givers of those pairs sees their target change.
+pub fn join<R: Rng + ?Sized>(
+    dbc: &mut Connection,
+    party: PartyRecord,
+    newcomer: Participant,
+    rng: &mut R,
+) -> Result<Arrival> {
+    let tx = dbc.transaction()?;
+    let party_id = party.id.clone();
+    if party.white_elephant && elephant::load(&tx, &party_id)?.is_some() {
+        return Ok(Arrival::GameStarted);
+    }
+    tx.execute(
+        "INSERT INTO participants
+            (party_id, uid, name, hint, team_id, region, ships_abroad, budget, interests)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            party_id,
+            newcomer.uid,
+            newcomer.name,
+            newcomer.hint,
+            newcomer.team_id,
+            newcomer.region,
+            newcomer.ships_abroad,
+            newcomer.budget,
+            newcomer.interests
+        ],
+    )?;
+    tx.execute(
+        "DELETE FROM party_admissions WHERE party_id = ?1 AND uid = ?2",
+        params![party_id, newcomer.uid],
+    )?;
+    if !is_drawn(&tx, &party_id)? {
+        tx.commit()?;
+        return Ok(Arrival::Joined);
+    }
+    let input = DrawInput::load(&tx, party)?;
+    let givers = if let Some(team_id) = newcomer.team_id {
+        let team_name = input
+            .teams
+            .iter()
+            .find(|(id, _)| *id == team_id)
+            .map(|(_, team_name)| team_name.clone())
+            .unwrap_or_default();
+        // A team gives and receives as one, so the newcomer copies their teammates' rows
+        let copied = tx.execute(
+            "INSERT INTO assignments
+                (party_id, giver_id, receiver_id, receiver_name, receiver_hint, loop_id, loop_position, receiver_team)
+            SELECT DISTINCT party_id, ?2, receiver_id, receiver_name, receiver_hint, loop_id, loop_position, receiver_team
+                FROM assignments WHERE party_id = ?1 AND giver_id IN
+                    (SELECT uid FROM participants WHERE party_id = ?1 AND team_id = ?3)",
+            params![party_id, newcomer.uid, team_id],
+        )?;
+        if copied == 0 {
+            return Ok(Arrival::Stuck(matching::MatchError::CannotInsert(
+                newcomer.uid,
+            )));
+        }
+        tx.execute(
+            "INSERT INTO assignments
+                (party_id, giver_id, receiver_id, receiver_name, receiver_hint, loop_id, loop_position, receiver_team)
+            SELECT DISTINCT party_id, giver_id, ?2, ?3, ?4, loop_id, loop_position, receiver_team
+                FROM assignments WHERE party_id = ?1 AND receiver_team = ?5",
+            params![
+                party_id,
+                newcomer.uid,
+                newcomer.name,
+                newcomer.hint,
+                team_name
+            ],
+        )?;
+        tx.prepare(
+            "SELECT DISTINCT giver_id FROM assignments
+                WHERE party_id = ?1 AND receiver_id = ?2 ORDER BY giver_id",
+        )?
+        .query_map(params![party_id, newcomer.uid], |row| {
+            row.get::<_, u64>("giver_id")
+        })?
+        .collect::<Result<Vec<_>>>()?
+    } else {
+        let pairs = current_pairs(&tx, &party_id)?
+            .into_iter()
+            .map(|(giver, receiver)| matching::Pair { giver, receiver })
+            .collect::<Vec<_>>();
+        let (_, rules) = input.rules();
+        let grown = match matching::insert(&pairs, newcomer.uid, &rules, rng) {
+            Ok(grown) => grown,
+            Err(e) => return Ok(Arrival::Stuck(e)),
+        };
+        let mut givers = Vec::new();
+        for pair in pairs.iter().filter(|pair| !grown.pairs().contains(pair)) {
+            // The newcomer takes over the row, and its giver gives to the newcomer instead
+            tx.execute(
+                "UPDATE assignments SET giver_id = ?2
+                    WHERE party_id = ?1 AND giver_id = ?3 AND receiver_id = ?4",
+                params![party_id, newcomer.uid, pair.giver, pair.receiver],
+            )?;
+            tx.execute(
+                "INSERT INTO assignments (party_id, giver_id, receiver_id, receiver_name, receiver_hint)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    party_id,
+                    pair.giver,
+                    newcomer.uid,
+                    newcomer.name,
+                    newcomer.hint
+                ],
+            )?;
+            givers.push(pair.giver);
+        }
+        for (giver, (loop_id, loop_position)) in loop_positions(&grown) {
+            set_loop(&tx, &party_id, giver, loop_id, loop_position)?;
+        }
+        givers.sort();
+        givers.dedup();
+        givers
+    };
+    tx.commit()?;
+    Ok(Arrival::Inserted(givers))
+}
+
+fn set_loop(
+    dbc: &Connection,
+    party_id: &str,
+    giver: u64,
+    loop_id: usize,
+    loop_position: usize,
+) -> Result<()> {
+    dbc.execute(
+        "UPDATE assignments SET loop_id = ?3, loop_position = ?4 WHERE party_id = ?1 AND giver_id = ?2",
+        params![party_id, giver, loop_id, loop_position],
+    )?;
+    Ok(())
+}
+
+/// Everyone signed up to a party
+pub fn signed_up(dbc: &Connection, party_id: &str) -> Result<Vec<u64>> {
+    dbc.prepare("SELECT uid FROM participants WHERE party_id = ?1 ORDER BY uid")?
+        .query_map([party_id], |row| row.get::<_, u64>("uid"))?
+        .collect()
+}
+
+/// Whether a party has assignments, which white elephant parties never do
+pub fn is_drawn(dbc: &Connection, party_id: &str) -> Result<bool> {
+    dbc.prepare("SELECT 1 FROM assignments WHERE party_id = ?1")?
+        .exists([party_id])
+}
+
+/// Marks a party as cancelled, so it is never drawn.
+/// Returns false if it was already drawn or cancelled.
+pub fn cancel(dbc: &Connection, party_id: &str) -> Result<bool> {
+    lifecycle::transition(dbc, party_id, PartyState::Cancelled)
+}
+
+pub fn party_teams(dbc: &Connection, party_id: &str) -> Result<Vec<(u64, String)>> {
+    dbc.prepare("SELECT team_id, team_name FROM party_teams WHERE party_id = ?1 ORDER BY team_id")?
+        .query_map([party_id], |row| {
+            Ok((
+                row.get::<_, u64>("team_id")?,
+                row.get::<_, String>("team_name")?,
+            ))
+        })?
+        .collect()
+}
+
+/// Replaces the party's assignments with `rows` and marks the party as drawn, failing if it
+/// can't be drawn any more. Callers run this inside a transaction, so a crash or a failure
+/// can't leave half a draw behind.
+pub fn write_matches(dbc: &Connection, party_id: &str, rows: &[MatchRow]) -> Result<()> {
+    if !lifecycle::transition(dbc, party_id, PartyState::Drawn)? {
+        return Err(async_sqlite::rusqlite::Error::StatementChangedRows(0));
+    }
+    dbc.execute("DELETE FROM assignments WHERE party_id = ?1", [party_id])?;
+    for row in rows {
+        dbc.execute(
+            "INSERT INTO assignments
+                (party_id, giver_id, receiver_id, receiver_name, receiver_hint, loop_id, loop_position, receiver_team)
+            VALUES
+                (?1,       ?2,       ?3,          ?4,            ?5,            ?6,      ?7,            ?8)",
+            params![
+                party_id,
+                row.giver_id,
+                row.receiver_id,
+                row.receiver_name,
+                row.receiver_hint,
+                row.loop_id,
+                row.loop_position,
+                row.receiver_team
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Stores a party's first draw and seals it with the `input` it was drawn from, in one
+/// transaction. Returns the commitment, or `None` if the party is no longer waiting for its draw,
+/// so running a draw twice is harmless.
+pub fn record_draw(
+    dbc: &mut Connection,
+    party_id: &str,
+    input: &str,
+    rows: &[MatchRow],
+    seed: u64,
+) -> Result<Option<String>> {
+    let tx = dbc.transaction()?;
+    if lifecycle::current(&tx, party_id)? != PartyState::Closed {
+        return Ok(None);
+    }
+    write_matches(&tx, party_id, rows)?;
+    let commitment = seal(&tx, party_id, seed, input, rows)?;
+    tx.commit()?;
+    Ok(Some(commitment))
+}
+
+/// Seals the round the party is on: stores the seed it was drawn with alongside the commitment
+/// to it, the dumped `input` and `rows`, replacing the round's earlier seal after a redraw.
+/// Returns the commitment, which is safe to publish right away.
+pub fn seal(
+    dbc: &Connection,
+    party_id: &str,
+    seed: u64,
+    input: &str,
+    rows: &[MatchRow],
+) -> Result<String> {
+    let record = audit::canonical(rows.iter().map(|row| (row.giver_id, row.receiver_id)));
+    let commitment = audit::commitment(seed, &audit::sealed_text(input, &record));
+    dbc.execute(
+        "INSERT OR REPLACE INTO party_seals (party_id, round, seed, input, record, commitment)
+            SELECT id, current_round, ?2, ?3, ?4, ?5 FROM parties WHERE id = ?1",
+        params![party_id, seed.to_string(), input, record, commitment],
+    )?;
+    Ok(commitment)
+}
+
+/// What one round of a party's draw was sealed with, kept secret until the reveal
+pub struct SealedDraw {
+    pub round: u32,
+    pub seed: u64,
+    /// Draws sealed before their input was have only their record to show
+    pub input: Option<String>,
+    pub record: String,
+    pub commitment: String,
+}
+
+impl SealedDraw {
+    /// The text the commitment was made over, which is published at the reveal
+    pub fn text(&self) -> String {
+        match &self.input {
+            Some(input) => audit::sealed_text(input, &self.record),
+            None => self.record.clone(),
+        }
+    }
+}
+
+/// The seal of every round of a party, first round first.
+/// Parties drawn before draws were sealed have nothing to reveal.
+pub fn sealed_rounds(dbc: &Connection, party_id: &str) -> Result<Vec<SealedDraw>> {
+    let seals = dbc
+        .prepare(
+            "SELECT round, seed, input, record, commitment FROM party_seals
+                WHERE party_id = ?1 ORDER BY round",
+        )?
+        .query_map([party_id], |row| {
+            Ok((
+                row.get::<_, u32>("round")?,
+                row.get::<_, String>("seed")?,
+                row.get::<_, Option<String>>("input")?,
+                row.get::<_, String>("record")?,
+                row.get::<_, String>("commitment")?,
+            ))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(seals
+        .into_iter()
+        .filter_map(|(round, seed, input, record, commitment)| {
+            Some(SealedDraw {
+                round,
+                seed: seed.parse().ok()?,
+                input,
+                record,
+                commitment,
+            })
+        })
+        .collect())
+}
+
+/// Moves a drawn party on to its reveal. A revealed party whose seals didn't all make it out
+/// is still waiting for them, so they can be sent again. Returns whether they should be
+/// published now.
+pub fn start_reveal(dbc: &Connection, party_id: &str) -> Result<bool> {
+    if lifecycle::transition(dbc, party_id, PartyState::Revealed)? {
+        return Ok(true);
+    }
+    dbc.prepare("SELECT 1 FROM parties WHERE id = ?1 AND state = ?2 AND NOT seals_published")?
+        .exists(params![party_id, PartyState::Revealed])
+}
+
+/// Records that every seal of a party was published
+pub fn finish_reveal(dbc: &Connection, party_id: &str) -> Result<()> {
+    dbc.execute(
+        "UPDATE parties SET seals_published = true WHERE id = ?1",
+        [party_id],
+    )?;
+    Ok(())
+}
+
+/// The party's matches as they are now, in the same form as [`SealedDraw::record`]
+pub fn current_record(dbc: &Connection, party_id: &str) -> Result<String> {
+    Ok(audit::canonical(current_pairs(dbc, party_id)?))
+}
+
+/// The party's giver and receiver pairs as they are now, which is none before the draw
+pub fn current_pairs(dbc: &Connection, party_id: &str) -> Result<Vec<(u64, u64)>> {
+    dbc.prepare("SELECT giver_id, receiver_id FROM assignments WHERE party_id = ?1")?
+        .query_map([party_id], |row| {
+            Ok((
+                row.get::<_, u64>("giver_id")?,
+                row.get::<_, u64>("receiver_id")?,
+            ))
+        })?
+        .collect()
+}
+
+/// Moves a multi-round party on to `round`. The current round's matches are archived
+/// to `party_rounds` and replaced with `rows`, which are sealed with `seed` and `input`.
+/// Returns the new round's commitment, or `None` if the party isn't on the round before,
+/// so drawing the same round twice is harmless.
+pub fn advance_round(
+    dbc: &mut Connection,
+    party_id: &str,
+    round: u32,
+    input: &str,
+    rows: &[MatchRow],
+    seed: u64,
+) -> Result<Option<String>> {
+    let tx = dbc.transaction()?;
+    let current_round = tx.query_one(
+        "SELECT current_round FROM parties WHERE id = ?1",
+        [party_id],
+        |row| row.get::<_, u32>("current_round"),
+    )?;
+    if current_round + 1 != round || lifecycle::current(&tx, party_id)? != PartyState::Drawn {
+        return Ok(None);
+    }
+    tx.execute(
+        "INSERT INTO party_rounds (party_id, round, giver_id, receiver_id, receiver_name, receiver_hint, receiver_team)
+            SELECT party_id, ?2, giver_id, receiver_id, receiver_name, receiver_hint, receiver_team
+                FROM assignments WHERE party_id = ?1",
+        params![party_id, current_round],
+    )?;
+    write_matches(&tx, party_id, rows)?;
+    tx.execute(
+        "UPDATE parties SET current_round = ?1 WHERE id = ?2",
+        params![round, party_id],
+    )?;
+    let commitment = seal(&tx, party_id, seed, input, rows)?;
+    tx.commit()?;
+    Ok(Some(commitment))
+}
+
+/// Replaces a drawn party's matches with `rows` in one transaction and records who asked for it.
+/// Returns the givers whose receivers changed and the new draw's commitment, or `None` if the
+/// party was revealed or otherwise moved on in the meantime, since its seal is final then.
+pub fn redraw_matches(
+    dbc: &mut Connection,
+    party_id: &str,
+    input: &str,
+    rows: &[MatchRow],
+    admin_id: u64,
+    redrawn_at: i64,
+    seed: u64,
+) -> Result<Option<(Vec<u64>, String)>> {
+    let tx = dbc.transaction()?;
+    if !matches!(
+        lifecycle::current(&tx, party_id)?,
+        PartyState::Closed | PartyState::Drawn
+    ) {
+        return Ok(None);
+    }
+    let mut before = HashMap::<u64, Vec<u64>>::new();
+    for (giver, receiver) in current_pairs(&tx, party_id)? {
+        before.entry(giver).or_default().push(receiver);
+    }
+    write_matches(&tx, party_id, rows)?;
+    let commitment = seal(&tx, party_id, seed, input, rows)?;
+    tx.execute(
+        "INSERT INTO party_redraws (party_id, admin_id, redrawn_at) VALUES (?1, ?2, ?3)",
+        params![party_id, admin_id, redrawn_at],
+    )?;
+    tx.commit()?;
+    let mut after = HashMap::<u64, Vec<u64>>::new();
+    for row in rows {
+        after.entry(row.giver_id).or_default().push(row.receiver_id);
+    }
+    let mut changed = after
+        .into_iter()
+        .filter_map(|(giver, mut receivers)| {
+            receivers.sort();
+            let mut previous = before.remove(&giver).unwrap_or_default();
+            previous.sort();
+            (receivers != previous).then_some(giver)
+        })
+        .collect::<Vec<_>>();
+    changed.sort();
+    Ok(Some((changed, commitment)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn party() -> PartyRecord {
+        PartyRecord {
+            id: "party".to_owned(),
+            admin_id: 1,
+            party_name: "Party".to_owned(),
+            ends_at: 0,
+            follows_id: None,
+            history_depth: 1,
+            single_loop: false,
+            gifts_per_person: 1,
+            channel_id: None,
+            regional: false,
+            budget_tiers: false,
+            white_elephant: false,
+            steal_limit: 3,
+            rounds: 1,
+            current_round: 1,
+            min_participants: 2,
+            state: PartyState::Drawn,
+            ship_by: None,
+        }
+    }
+
+    /// A migrated database holding a party called `party` whose signups have closed
+    fn dbc() -> Connection {
+        let dbc = Connection::open_in_memory().unwrap();
+        schema::migrate(&dbc).unwrap();
+        dbc.execute(
+            "INSERT INTO parties (id, admin_id, party_name, started_at, ends_at, state)
+                VALUES ('party', 1, 'Party', 0, 0, 'closed')",
+            [],
+        )
+        .unwrap();
+        dbc
+    }
+
+    fn participant(uid: u64, team_id: Option<u64>) -> Participant {
+        Participant {
+            uid,
+            name: format!("name {uid}"),
+            hint: format!("hint {uid}"),
+            team_id,
+            region: None,
+            ships_abroad: false,
+            budget: None,
+            interests: None,
+        }
+    }
+
+    #[test]
+    fn team_draws_gift_every_member_of_the_receiving_team() {
+        let input = DrawInput {
+            party: party(),
+            participants: vec![
+                participant(10, Some(1)),
+                participant(11, Some(1)),
+                participant(20, Some(2)),
+                participant(30, Some(3)),
+                participant(31, Some(3)),
+            ],
+            exclusions: vec![(10, 20)],
+            history: vec![],
+            pins: vec![],
+            teams: vec![
+                (1, "Red".to_owned()),
+                (2, "Green".to_owned()),
+                (3, "Blue".to_owned()),
+            ],
+        };
+        let (ids, rules) = input.rules();
+        assert_eq!(ids, vec![1, 2, 3]);
+        // Red and Green may not gift each other, which leaves Blue as the only option for both
+        assert!(!rules.allows(1, 2));
+        let assignment = matching::draw(&ids, &rules, &mut ChaCha20Rng::seed_from_u64(0));
+        assert_eq!(assignment, Err(matching::MatchError::Unsatisfiable));
+
+        let input = DrawInput {
+            exclusions: vec![],
+            ..input
+        };
+        let (ids, rules) = input.rules();
+        let assignment = matching::draw(&ids, &rules, &mut ChaCha20Rng::seed_from_u64(0)).unwrap();
+        let rows = input.rows(&assignment);
+        let receivers_of = |giver: u64| {
+            rows.iter()
+                .filter(|row| row.giver_id == giver)
+                .map(|row| (row.receiver_id, row.receiver_team.clone().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(receivers_of(10), receivers_of(11));
+        assert_eq!(receivers_of(30), receivers_of(31));
+        assert!(receivers_of(10).iter().all(|(uid, _)| *uid >= 20));
+        assert_eq!(
+            rows.len(),
+            2 * receivers_of(10).len() + receivers_of(20).len() + 2 * receivers_of(30).len()
+        );
+    }
+
+    #[test]
+    fn dumped_inputs_replay_the_same_draw() {
+        let mut party = party();
+        party.regional = true;
+        party.budget_tiers = true;
+        let input = DrawInput {
+            party,
+            participants: (1..=8)
+                .map(|uid| Participant {
+                    region: Some(if uid <= 4 { "Europe" } else { "north america" }.to_owned()),
+                    ships_abroad: uid == 8,
+                    budget: Some(if uid <= 4 { 10 } else { 25 }),
+                    interests: (uid < 4).then(|| "board games,coffee".to_owned()),
+                    ..participant(uid, None)
+                })
+                .collect(),
+            exclusions: vec![(1, 3)],
+            history: vec![vec![(2, 4), (4, 2)], vec![]],
+            teams: vec![],
+            pins: vec![(6, 8)],
+        };
+        let dump = input.dump();
+        let parsed = DrawInput::parse(&dump).unwrap();
+        assert_eq!(parsed.dump(), dump);
+        let record = |input: &DrawInput| {
+            let (ids, rules) = input.rules();
+            let assignment =
+                matching::draw(&ids, &rules, &mut ChaCha20Rng::seed_from_u64(11)).unwrap();
+            audit::canonical(
+                input
+                    .rows(&assignment)
+                    .iter()
+                    .map(|row| (row.giver_id, row.receiver_id)),
+            )
+        };
+        assert_eq!(record(&parsed), record(&input));
+        assert!(DrawInput::parse("gifts many\n").is_err());
+        assert!(
+            DrawInput::parse("budget 1 10\n").is_err(),
+            "1 was never listed"
+        );
+    }
+
+    #[test]
+    fn parties_need_enough_ids_for_every_gift() {
+        let input = DrawInput {
+            party: party(),
+            participants: vec![participant(10, Some(1)), participant(11, Some(1))],
+            exclusions: vec![],
+            history: vec![],
+            pins: vec![],
+            teams: vec![(1, "Red".to_owned()), (2, "Green".to_owned())],
+        };
+        // Two people, but both on the same team
+        assert_eq!(input.shortfall(), Some((2, 1)));
+        let mut party = party();
+        party.gifts_per_person = 3;
+        let input = DrawInput {
+            party,
+            participants: vec![
+                participant(1, None),
+                participant(2, None),
+                participant(3, None),
+            ],
+            teams: vec![],
+            ..input
+        };
+        assert_eq!(input.shortfall(), Some((4, 3)));
+        let input = DrawInput {
+            participants: (1..=4).map(|uid| participant(uid, None)).collect(),
+            ..input
+        };
+        assert_eq!(input.shortfall(), None);
+    }
+
+    fn row(giver_id: u64, receiver_id: u64) -> MatchRow {
+        MatchRow {
+            giver_id,
+            receiver_id,
+            receiver_name: format!("name {receiver_id}"),
+            receiver_hint: format!("hint {receiver_id}"),
+            loop_id: 0,
+            loop_position: 0,
+            receiver_team: None,
+        }
+    }
+
+    #[test]
+    fn redraws_replace_matches_and_report_changed_givers() {
+        let mut dbc = dbc();
+        write_matches(&dbc, "party", &[row(1, 2), row(2, 3), row(3, 4), row(4, 1)]).unwrap();
+        let rows = [row(1, 2), row(2, 4), row(4, 1)];
+        let (changed, commitment) =
+            redraw_matches(&mut dbc, "party", "gifts 1\n", &rows, 1, 100, 9)
+                .unwrap()
+                .unwrap();
+        assert_eq!(changed, vec![2]);
+        assert_eq!(
+            commitment,
+            audit::commitment(9, &audit::sealed_text("gifts 1\n", "1>2;2>4;4>1"))
+        );
+        let matches: i64 = dbc
+            .query_one(
+                "SELECT COUNT(*) FROM assignments WHERE party_id = 'party'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(matches, 3);
+        let admin: u64 = dbc
+            .query_one(
+                "SELECT admin_id FROM party_redraws WHERE party_id = 'party'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(admin, 1);
+        // Once the seed is out, a redraw racing the reveal must leave the sealed pairs alone
+        dbc.execute("UPDATE parties SET state = 'revealed'", [])
+            .unwrap();
+        assert_eq!(
+            redraw_matches(&mut dbc, "party", "", &[row(1, 4), row(4, 1)], 1, 200, 10).unwrap(),
+            None
+        );
+        assert!(write_matches(&dbc, "party", &[row(1, 4), row(4, 1)]).is_err());
+        assert_eq!(current_pairs(&dbc, "party").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn cancelling_marks_the_party() {
+        let dbc = dbc();
+        dbc.execute("UPDATE parties SET min_participants = 3", [])
+            .unwrap();
+        dbc.execute_batch(
+            "INSERT INTO participants (party_id, uid, name, hint) VALUES ('party', 7, 'g', 'g');",
+        )
+        .unwrap();
+        assert_eq!(signed_up(&dbc, "party").unwrap(), vec![7]);
+        assert!(cancel(&dbc, "party").unwrap());
+        let state: PartyState = dbc
+            .query_one("SELECT state FROM parties WHERE id = 'party'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(state, PartyState::Cancelled);
+        assert!(!cancel(&dbc, "party").unwrap());
+    }
+
+    #[test]
+    fn reveals_are_sent_until_they_get_out() {
+        let dbc = dbc();
+        assert!(!start_reveal(&dbc, "party").unwrap());
+        dbc.execute("UPDATE parties SET state = 'drawn'", [])
+            .unwrap();
+        assert!(start_reveal(&dbc, "party").unwrap());
+        // Sending it failed, so the retry publishes it again
+        assert!(start_reveal(&dbc, "party").unwrap());
+        finish_reveal(&dbc, "party").unwrap();
+        assert!(!start_reveal(&dbc, "party").unwrap());
+    }
+
+    #[test]
+    fn drawing_twice_keeps_the_first_draw() {
+        let mut dbc = dbc();
+        // Left behind by a draw that crashed before draws were written in one transaction
+        dbc.execute(
+            "INSERT INTO assignments (party_id, giver_id, receiver_id, receiver_name, receiver_hint)
+                VALUES ('party', 1, 2, 'b', 'b')",
+            [],
+        )
+        .unwrap();
+        let rows = [row(1, 2), row(2, 3), row(3, 1)];
+        assert!(
+            record_draw(&mut dbc, "party", "", &rows, 4)
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(current_pairs(&dbc, "party").unwrap().len(), 3);
+        assert_eq!(
+            record_draw(&mut dbc, "party", "", &[row(1, 3), row(3, 2), row(2, 1)], 5).unwrap(),
+            None
+        );
+        let mut pairs = current_pairs(&dbc, "party").unwrap();
+        pairs.sort();
+        assert_eq!(pairs, vec![(1, 2), (2, 3), (3, 1)]);
+        let seals = sealed_rounds(&dbc, "party").unwrap();
+        assert_eq!(
+            seals
+                .iter()
+                .map(|sealed| (sealed.round, sealed.seed))
+                .collect::<Vec<_>>(),
+            vec![(1, 4)]
+        );
+    }
+
+    #[test]
+    fn later_rounds_avoid_every_earlier_round() {
+        let mut dbc = dbc();
+        dbc.execute("UPDATE parties SET rounds = 12", []).unwrap();
+        write_matches(&dbc, "party", &[row(1, 2), row(2, 3), row(3, 1)]).unwrap();
+        advance_round(
+            &mut dbc,
+            "party",
+            2,
+            "",
+            &[row(1, 3), row(3, 2), row(2, 1)],
+            5,
+        )
+        .unwrap()
+        .unwrap();
+        // Drawing day 2 again does nothing
+        assert_eq!(
+            advance_round(
+                &mut dbc,
+                "party",
+                2,
+                "",
+                &[row(1, 2), row(2, 3), row(3, 1)],
+                7
+            )
+            .unwrap(),
+            None
+        );
+        let mut pairs = current_pairs(&dbc, "party").unwrap();
+        pairs.sort();
+        assert_eq!(pairs, vec![(1, 3), (2, 1), (3, 2)]);
+        advance_round(
+            &mut dbc,
+            "party",
+            3,
+            "",
+            &[row(1, 2), row(2, 3), row(3, 1)],
+            6,
+        )
+        .unwrap()
+        .unwrap();
+        // Every day keeps its own seal, so the commitments of earlier days can still be checked
+        let seals = sealed_rounds(&dbc, "party").unwrap();
+        assert_eq!(
+            seals
+                .iter()
+                .map(|sealed| (sealed.round, sealed.seed))
+                .collect::<Vec<_>>(),
+            vec![(2, 5), (3, 6)]
+        );
+        assert_eq!(
+            seals[0].commitment,
+            audit::commitment(5, &audit::sealed_text("", "1>3;2>1;3>2"))
+        );
+        let mut input = DrawInput::load(&dbc, party()).unwrap();
+        for pairs in &mut input.history {
+            pairs.sort();
+        }
+        assert_eq!(
+            input.history,
+            vec![vec![(1, 3), (2, 1), (3, 2)], vec![(1, 2), (2, 3), (3, 1)]]
+        );
+    }
+
+    #[test]
+    fn leaving_a_drawn_party_only_changes_one_giver() {
+        let mut dbc = dbc();
+        dbc.execute_batch(
+            "INSERT INTO participants (party_id, uid, name, hint) VALUES
+                ('party', 1, 'a', 'a'), ('party', 2, 'b', 'b'), ('party', 3, 'c', 'c'), ('party', 4, 'd', 'd');",
+        )
+        .unwrap();
+        write_matches(&dbc, "party", &[row(1, 2), row(2, 3), row(3, 4), row(4, 1)]).unwrap();
+        assert_eq!(
+            leave(&mut dbc, party(), 3, false).unwrap(),
+            Departure::Spliced(vec![2])
+        );
+        let name: String = dbc
+            .query_one(
+                "SELECT receiver_name FROM assignments WHERE party_id = 'party' AND giver_id = 2",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(name, "name 4");
+        let matches: i64 = dbc
+            .query_one(
+                "SELECT COUNT(*) FROM assignments WHERE party_id = 'party'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(matches, 3);
+        assert_eq!(
+            leave(&mut dbc, party(), 3, false).unwrap(),
+            Departure::NotJoined
+        );
+        // 1, 2 and 4 are now a loop of three, and 2 can't be left giving to themselves
+        assert_eq!(
+            leave(&mut dbc, party(), 1, false).unwrap(),
+            Departure::Spliced(vec![4])
+        );
+        assert_eq!(
+            leave(&mut dbc, party(), 2, false).unwrap(),
+            Departure::Stuck(matching::MatchError::CannotSplice(2))
+        );
+        // Forcing them out leaves the rest of the party for a redraw
+        assert_eq!(
+            leave(&mut dbc, party(), 2, true).unwrap(),
+            Departure::Stuck(matching::MatchError::CannotSplice(2))
+        );
+        assert_eq!(
+            leave(&mut dbc, party(), 2, false).unwrap(),
+            Departure::NotJoined
+        );
+    }
+
+    #[test]
+    fn late_joiners_are_slotted_into_the_drawn_loop() {
+        let mut dbc = dbc();
+        dbc.execute_batch(
+            "INSERT INTO participants (party_id, uid, name, hint) VALUES
+                ('party', 1, 'a', 'a'), ('party', 2, 'b', 'b');",
+        )
+        .unwrap();
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        assert_eq!(
+            join(&mut dbc, party(), participant(3, None), &mut rng).unwrap(),
+            Arrival::Joined
+        );
+        write_matches(&dbc, "party", &[row(1, 2), row(2, 3), row(3, 1)]).unwrap();
+        let Arrival::Inserted(givers) =
+            join(&mut dbc, party(), participant(4, None), &mut rng).unwrap()
+        else {
+            panic!("4 should have been inserted");
+        };
+        assert_eq!(givers.len(), 1);
+        let receivers_of = |giver: u64| -> Vec<u64> {
+            dbc.prepare(
+                "SELECT receiver_id FROM assignments WHERE party_id = 'party' AND giver_id = ?1",
+            )
+            .unwrap()
+            .query_map([giver], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap()
+        };
+        assert_eq!(receivers_of(givers[0]), vec![4]);
+        assert_eq!(receivers_of(4), vec![givers[0] % 3 + 1]);
+        assert!(
+            join(&mut dbc, party(), participant(4, None), &mut rng).is_err(),
+            "joining twice is refused"
+        );
+    }
+
+    #[test]
+    fn nobody_joins_an_elephant_game_that_has_started() {
+        let mut dbc = dbc();
+        let party = || PartyRecord {
+            white_elephant: true,
+            ..party()
+        };
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        assert_eq!(
+            join(&mut dbc, party(), participant(1, None), &mut rng).unwrap(),
+            Arrival::Joined
+        );
+        let game = elephant::Game::new(&[1], 3, &mut rng);
+        elephant::save(&dbc, "party", &game).unwrap();
+        assert_eq!(
+            join(&mut dbc, party(), participant(2, None), &mut rng).unwrap(),
+            Arrival::GameStarted
+        );
+        assert_eq!(signed_up(&dbc, "party").unwrap(), vec![1]);
+    }
+}
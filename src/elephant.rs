@@ -0,0 +1,325 @@
+use async_sqlite::rusqlite::{Connection, OptionalExtension, Result, params};
+use rand::{
+    Rng,
+    seq::{IndexedRandom, SliceRandom},
+};
+
+/// One wrapped gift, brought by a participant and opened by whoever picks it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gift {
+    pub brought_by: u64,
+    /// What the opener found inside
+    pub description: Option<String>,
+    pub holder: Option<u64>,
+    pub steals: u32,
+}
+
+/// A white elephant game: everyone takes a turn in a random order, and each turn either opens
+/// a new gift or steals an opened one. Whoever is stolen from goes again right away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Game {
+    order: Vec<u64>,
+    /// The next player in `order` who hasn't had their turn
+    turn: usize,
+    /// Whoever has to move now, which is a victim of a steal or the next player in order
+    up: Option<u64>,
+    gifts: Vec<Gift>,
+    steal_limit: u32,
+    /// A gift can't be stolen straight back by the person it was just taken from
+    last_stolen: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveError {
+    GameOver,
+    NotYourTurn,
+    NothingToSteal,
+    Frozen,
+    NoStealBack,
+}
+impl std::fmt::Display for MoveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoveError::GameOver => write!(f, "the game is over"),
+            MoveError::NotYourTurn => write!(f, "it's not your turn"),
+            MoveError::NothingToSteal => write!(f, "they aren't holding an opened gift"),
+            MoveError::Frozen => write!(f, "that gift has been stolen too often to move again"),
+            MoveError::NoStealBack => {
+                write!(f, "you can't steal back the gift just taken from you")
+            }
+        }
+    }
+}
+
+impl Game {
+    /// Starts a game with one gift per participant and a random turn order
+    pub fn new<R: Rng + ?Sized>(participants: &[u64], steal_limit: u32, rng: &mut R) -> Self {
+        let mut order = participants.to_vec();
+        order.shuffle(rng);
+        Game {
+            up: order.first().copied(),
+            turn: 0,
+            gifts: participants
+                .iter()
+                .map(|&brought_by| Gift {
+                    brought_by,
+                    description: None,
+                    holder: None,
+                    steals: 0,
+                })
+                .collect(),
+            order,
+            steal_limit,
+            last_stolen: None,
+        }
+    }
+    pub fn order(&self) -> &[u64] {
+        &self.order
+    }
+    /// Whoever has to move now, or `None` once the game is over
+    pub fn up(&self) -> Option<u64> {
+        self.up
+    }
+    /// Unwraps a random unopened gift, preferring one the player didn't bring themselves.
+    /// Returns the opened gift.
+    pub fn open<R: Rng + ?Sized>(
+        &mut self,
+        player: u64,
+        description: String,
+        rng: &mut R,
+    ) -> Result<&Gift, MoveError> {
+        self.check_turn(player)?;
+        let unopened = (0..self.gifts.len())
+            .filter(|&i| self.gifts[i].holder.is_none())
+            .collect::<Vec<_>>();
+        let others = unopened
+            .iter()
+            .copied()
+            .filter(|&i| self.gifts[i].brought_by != player)
+            .collect::<Vec<_>>();
+        let Some(&gift) = others.choose(rng).or_else(|| unopened.choose(rng)) else {
+            return Err(MoveError::GameOver);
+        };
+        self.gifts[gift].description = Some(description);
+        self.gifts[gift].holder = Some(player);
+        self.last_stolen = None;
+        self.next_turn();
+        Ok(&self.gifts[gift])
+    }
+    /// Takes `victim`'s gift, after which the victim has to move. Returns the stolen gift.
+    pub fn steal(&mut self, player: u64, victim: u64) -> Result<&Gift, MoveError> {
+        self.check_turn(player)?;
+        let Some(gift) = self
+            .gifts
+            .iter()
+            .position(|gift| gift.holder == Some(victim) && victim != player)
+        else {
+            return Err(MoveError::NothingToSteal);
+        };
+        if self.gifts[gift].steals >= self.steal_limit {
+            return Err(MoveError::Frozen);
+        }
+        if self.last_stolen == Some(gift) {
+            return Err(MoveError::NoStealBack);
+        }
+        self.gifts[gift].holder = Some(player);
+        self.gifts[gift].steals += 1;
+        self.last_stolen = Some(gift);
+        self.up = Some(victim);
+        Ok(&self.gifts[gift])
+    }
+    /// Describes where the game stands, or who ended up with what once it's over
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        let opened = self.gifts.iter().filter(|gift| gift.holder.is_some());
+        match self.up {
+            Some(up) => {
+                for gift in opened {
+                    summary.push_str(&format!(
+                        "- <@{}> holds **{}** (stolen {}/{} times)\n",
+                        gift.holder.unwrap_or_default(),
+                        gift.description.as_deref().unwrap_or("a gift"),
+                        gift.steals,
+                        self.steal_limit
+                    ));
+                }
+                summary.push_str(&format!(
+                    "It's <@{up}>'s turn to `/elephant open` a gift or `/elephant steal` one."
+                ));
+            }
+            None => {
+                summary.push_str("The game is over! Here's who went home with what:\n");
+                for gift in opened {
+                    summary.push_str(&format!(
+                        "- <@{}> ends up with **{}**, brought by <@{}>\n",
+                        gift.holder.unwrap_or_default(),
+                        gift.description.as_deref().unwrap_or("a gift"),
+                        gift.brought_by
+                    ));
+                }
+            }
+        }
+        summary
+    }
+    fn check_turn(&self, player: u64) -> Result<(), MoveError> {
+        match self.up {
+            None => Err(MoveError::GameOver),
+            Some(up) if up != player => Err(MoveError::NotYourTurn),
+            Some(_) => Ok(()),
+        }
+    }
+    fn next_turn(&mut self) {
+        self.turn += 1;
+        self.up = self.order.get(self.turn).copied();
+    }
+}
+
+/// Stores a party's game, replacing any earlier state
+pub fn save(dbc: &Connection, party_id: &str, game: &Game) -> Result<()> {
+    let order = game
+        .order
+        .iter()
+        .map(|uid| uid.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    dbc.execute(
+        "INSERT OR REPLACE INTO elephant_games (party_id, turn_order, turn, up, steal_limit, last_stolen)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            party_id,
+            order,
+            game.turn,
+            game.up,
+            game.steal_limit,
+            game.last_stolen
+        ],
+    )?;
+    dbc.execute("DELETE FROM elephant_gifts WHERE party_id = ?1", [party_id])?;
+    for (gift_id, gift) in game.gifts.iter().enumerate() {
+        dbc.execute(
+            "INSERT INTO elephant_gifts (party_id, gift_id, brought_by, description, holder, steals)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                party_id,
+                gift_id,
+                gift.brought_by,
+                gift.description,
+                gift.holder,
+                gift.steals
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Loads a party's game, if it has started
+pub fn load(dbc: &Connection, party_id: &str) -> Result<Option<Game>> {
+    let Some(mut game) = dbc
+        .query_one(
+            "SELECT turn_order, turn, up, steal_limit, last_stolen FROM elephant_games WHERE party_id = ?1",
+            [party_id],
+            |row| {
+                Ok(Game {
+                    order: row
+                        .get::<_, String>("turn_order")?
+                        .split(',')
+                        .filter_map(|uid| uid.parse().ok())
+                        .collect(),
+                    turn: row.get::<_, usize>("turn")?,
+                    up: row.get::<_, Option<u64>>("up")?,
+                    gifts: Vec::new(),
+                    steal_limit: row.get::<_, u32>("steal_limit")?,
+                    last_stolen: row.get::<_, Option<usize>>("last_stolen")?,
+                })
+            },
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    game.gifts = dbc
+        .prepare(
+            "SELECT brought_by, description, holder, steals FROM elephant_gifts
+                WHERE party_id = ?1 ORDER BY gift_id",
+        )?
+        .query_map([party_id], |row| {
+            Ok(Gift {
+                brought_by: row.get::<_, u64>("brought_by")?,
+                description: row.get::<_, Option<String>>("description")?,
+                holder: row.get::<_, Option<u64>>("holder")?,
+                steals: row.get::<_, u32>("steals")?,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Some(game))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn everyone_ends_up_with_one_gift() {
+        let mut rng = ChaCha20Rng::seed_from_u64(17);
+        let mut game = Game::new(&[1, 2, 3, 4], 3, &mut rng);
+        let order = game.order().to_vec();
+        let [first, second, third, fourth] = order[..] else {
+            unreachable!()
+        };
+        assert_eq!(
+            game.open(second, "socks".to_owned(), &mut rng),
+            Err(MoveError::NotYourTurn)
+        );
+        let gift = game.open(first, "mug".to_owned(), &mut rng).unwrap();
+        assert_ne!(gift.brought_by, first);
+        game.steal(second, first).unwrap();
+        // The first player now has to move again, and can't take the mug straight back
+        assert_eq!(game.up(), Some(first));
+        assert_eq!(game.steal(first, second), Err(MoveError::NoStealBack));
+        game.open(first, "socks".to_owned(), &mut rng).unwrap();
+        assert_eq!(game.up(), Some(third));
+        game.steal(third, second).unwrap();
+        assert_eq!(game.steal(second, third), Err(MoveError::NoStealBack));
+        game.steal(second, first).unwrap();
+        game.open(first, "candle".to_owned(), &mut rng).unwrap();
+        assert_eq!(game.up(), Some(fourth));
+        game.steal(fourth, third).unwrap();
+        // The mug has now been stolen three times, which is the limit
+        assert_eq!(game.steal(third, fourth), Err(MoveError::Frozen));
+        assert_eq!(game.steal(third, third), Err(MoveError::NothingToSteal));
+        game.open(third, "book".to_owned(), &mut rng).unwrap();
+        assert_eq!(game.up(), None);
+        assert!(game.summary().starts_with("The game is over!"));
+        assert_eq!(
+            game.open(first, "nothing".to_owned(), &mut rng),
+            Err(MoveError::GameOver)
+        );
+        let mut holders = game
+            .gifts
+            .iter()
+            .map(|gift| gift.holder.unwrap())
+            .collect::<Vec<_>>();
+        holders.sort();
+        assert_eq!(holders, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn games_survive_a_round_trip_through_the_database() {
+        let dbc = Connection::open_in_memory().unwrap();
+        crate::schema::migrate(&dbc).unwrap();
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        let mut game = Game::new(&[10, 20, 30], 3, &mut rng);
+        let first = game.up().unwrap();
+        game.open(first, "scarf".to_owned(), &mut rng).unwrap();
+        let second = game.up().unwrap();
+        game.steal(second, first).unwrap();
+        save(&dbc, "party", &game).unwrap();
+        assert_eq!(load(&dbc, "party").unwrap(), Some(game.clone()));
+        game.open(first, "tea".to_owned(), &mut rng).unwrap();
+        save(&dbc, "party", &game).unwrap();
+        assert_eq!(load(&dbc, "party").unwrap(), Some(game));
+        assert_eq!(load(&dbc, "other").unwrap(), None);
+    }
+}
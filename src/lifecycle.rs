@@ -0,0 +1,144 @@
+use async_sqlite::rusqlite::{
+    Connection, Result, params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef},
+};
+
+/// Where a party is in its life, stored in `parties.state`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartyState {
+    /// Taking signups
+    Open,
+    /// Signups have closed, but the party hasn't been drawn
+    Closed,
+    /// Everyone has a target, or a white elephant game is being played
+    Drawn,
+    /// The draw's seed has been published, so the pairs are final
+    Revealed,
+    /// Too few people signed up
+    Cancelled,
+    /// Finished for good, and only kept around as history
+    Archived,
+}
+
+impl PartyState {
+    const ALL: [PartyState; 6] = [
+        PartyState::Open,
+        PartyState::Closed,
+        PartyState::Drawn,
+        PartyState::Revealed,
+        PartyState::Cancelled,
+        PartyState::Archived,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PartyState::Open => "open",
+            PartyState::Closed => "closed",
+            PartyState::Drawn => "drawn",
+            PartyState::Revealed => "revealed",
+            PartyState::Cancelled => "cancelled",
+            PartyState::Archived => "archived",
+        }
+    }
+
+    /// Whether a party in this state may move to `next`.
+    /// Drawn parties can be drawn again, for redraws and the rounds of advent parties.
+    pub fn can_become(self, next: PartyState) -> bool {
+        use PartyState::*;
+        matches!(
+            (self, next),
+            (Open, Closed)
+                | (Open | Closed, Cancelled)
+                | (Closed | Drawn, Drawn)
+                | (Drawn, Revealed)
+                | (Drawn | Revealed, Archived)
+        )
+    }
+
+    /// Whether people who joined can still leave or be let in
+    pub fn is_running(self) -> bool {
+        matches!(
+            self,
+            PartyState::Open | PartyState::Closed | PartyState::Drawn
+        )
+    }
+}
+
+impl std::fmt::Display for PartyState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl ToSql for PartyState {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for PartyState {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let state = value.as_str()?;
+        PartyState::ALL
+            .into_iter()
+            .find(|known| known.as_str() == state)
+            .ok_or_else(|| FromSqlError::Other(format!("unknown party state {state}").into()))
+    }
+}
+
+/// The state a party is in right now
+pub fn current(dbc: &Connection, party_id: &str) -> Result<PartyState> {
+    dbc.query_one(
+        "SELECT state FROM parties WHERE id = ?1",
+        [party_id],
+        |row| row.get::<_, PartyState>("state"),
+    )
+}
+
+/// Moves a party to `next` if its current state allows it. Returns whether it moved, so a
+/// party that was cancelled or drawn in the meantime is left alone.
+pub fn transition(dbc: &Connection, party_id: &str, next: PartyState) -> Result<bool> {
+    let allowed = PartyState::ALL
+        .into_iter()
+        .filter(|state| state.can_become(next))
+        .map(|state| format!("'{state}'"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let changed = dbc.execute(
+        &format!("UPDATE parties SET state = ?1 WHERE id = ?2 AND state IN ({allowed})"),
+        params![next, party_id],
+    )?;
+    Ok(changed > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_checked_transitions_go_through() {
+        let dbc = Connection::open_in_memory().unwrap();
+        crate::schema::migrate(&dbc).unwrap();
+        dbc.execute(
+            "INSERT INTO parties (id, admin_id, party_name, started_at, ends_at)
+                VALUES ('party', 1, 'Party', 0, 0)",
+            [],
+        )
+        .unwrap();
+        let state = || -> PartyState {
+            dbc.query_one("SELECT state FROM parties WHERE id = 'party'", [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(state(), PartyState::Open);
+        assert!(!transition(&dbc, "party", PartyState::Drawn).unwrap());
+        assert!(transition(&dbc, "party", PartyState::Closed).unwrap());
+        assert!(transition(&dbc, "party", PartyState::Drawn).unwrap());
+        assert!(transition(&dbc, "party", PartyState::Drawn).unwrap());
+        assert!(!transition(&dbc, "party", PartyState::Cancelled).unwrap());
+        assert!(transition(&dbc, "party", PartyState::Revealed).unwrap());
+        assert!(!transition(&dbc, "party", PartyState::Drawn).unwrap());
+        assert_eq!(state(), PartyState::Revealed);
+    }
+}
@@ -1,6 +1,9 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use async_sqlite::{Pool, rusqlite::params};
+use async_sqlite::{
+    Pool,
+    rusqlite::{OptionalExtension, params},
+};
 
 use base64::{Engine, prelude::BASE64_STANDARD};
 use color_eyre::{Result, eyre::eyre};
@@ -8,14 +11,23 @@ use poise::{
     ApplicationContext, Context, CreateReply, execute_modal_on_component_interaction,
     serenity_prelude::{
         self, ComponentInteractionDataKind, CreateActionRow, CreateButton, CreateEmbed,
-        CreateSelectMenu, CreateSelectMenuKind, CreateSelectMenuOption, GatewayIntents,
+        CreateMessage, CreateSelectMenu, CreateSelectMenuKind, CreateSelectMenuOption,
+        GatewayIntents,
     },
 };
-use rand::{Rng, RngCore, SeedableRng, seq::SliceRandom};
+use rand::{Rng, RngCore, SeedableRng};
 
+use lifecycle::PartyState;
 use tracing::{Level, event};
 use tracing_subscriber::util::SubscriberInitExt;
 mod app_errs;
+mod audit;
+mod draw;
+mod elephant;
+mod lifecycle;
+mod matching;
+mod scheduler;
+mod schema;
 
 struct AppState {
     db: Pool,
@@ -63,11 +75,881 @@ async fn info(ctx: AppContext<'_>) -> AppResult {
     Ok(())
 }
 
-#[poise::command(slash_command, subcommands("create", "join"))]
+#[poise::command(
+    slash_command,
+    subcommands(
+        "create", "join", "admit", "leave", "remove", "exclude", "pin", "preview", "redraw",
+        "reveal", "schedule"
+    )
+)]
 async fn party(_ctx: AppContext<'_>) -> AppResult {
     event!(Level::WARN, "Impossible parent command 'party' was called!");
     Ok(())
 }
+
+/// Recovers the party id a join phrase was generated from
+fn party_id_from_phrase(joinphrase: String) -> Option<uuid::Uuid> {
+    let mut v = Vec::default();
+    mnemonic::decode(joinphrase, &mut v).ok()?;
+    if v.len() != 4 {
+        return None;
+    }
+    let rng_seed = u32::from_be_bytes([v[0], v[1], v[2], v[3]]);
+    Some(
+        uuid::Builder::from_random_bytes({
+            let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(rng_seed as u64);
+            let mut v: [u8; 16] = [0; 16];
+            rng.fill_bytes(&mut v);
+            v
+        })
+        .into_uuid(),
+    )
+}
+
+struct PartyRecord {
+    id: String,
+    admin_id: u64,
+    party_name: String,
+    ends_at: i64,
+    follows_id: Option<String>,
+    history_depth: u32,
+    single_loop: bool,
+    gifts_per_person: u32,
+    /// Where the party was created, and where its draw is announced
+    channel_id: Option<u64>,
+    /// Whether givers are limited to receivers in their own shipping region
+    regional: bool,
+    /// Whether everyone picks a budget and is only matched with people who picked the same one
+    budget_tiers: bool,
+    /// Whether the party plays white elephant instead of drawing pairs
+    white_elephant: bool,
+    /// How often a gift can be stolen in a white elephant game before it stays put
+    steal_limit: u32,
+    /// How many daily rounds an advent party draws, which is 1 for a regular party
+    rounds: u32,
+    /// The round the party's assignments are for
+    current_round: u32,
+    /// The party is cancelled if fewer people than this have signed up when signups close
+    min_participants: u32,
+    state: PartyState,
+    /// When gifts have to be shipped, with a reminder going out the day before
+    ship_by: Option<i64>,
+}
+async fn find_party(
+    db: &Pool,
+    party_id: String,
+) -> Result<Option<PartyRecord>, async_sqlite::Error> {
+    db.conn(move |dbc| {
+        dbc.query_one(
+            "SELECT id, admin_id, party_name, ends_at, follows_id, history_depth, single_loop, gifts_per_person, channel_id, regional, budget_tiers, white_elephant, steal_limit, rounds, current_round, min_participants, state, ship_by FROM parties WHERE id = ?1",
+            [party_id],
+            |row| {
+                Ok(PartyRecord {
+                    id: row.get::<_, String>("id")?,
+                    admin_id: row.get::<_, u64>("admin_id")?,
+                    party_name: row.get::<_, String>("party_name")?,
+                    ends_at: row.get::<_, i64>("ends_at")?,
+                    follows_id: row.get::<_, Option<String>>("follows_id")?,
+                    history_depth: row.get::<_, u32>("history_depth")?,
+                    single_loop: row.get::<_, bool>("single_loop")?,
+                    gifts_per_person: row.get::<_, u32>("gifts_per_person")?,
+                    channel_id: row.get::<_, Option<u64>>("channel_id")?,
+                    regional: row.get::<_, bool>("regional")?,
+                    budget_tiers: row.get::<_, bool>("budget_tiers")?,
+                    white_elephant: row.get::<_, bool>("white_elephant")?,
+                    steal_limit: row.get::<_, u32>("steal_limit")?,
+                    rounds: row.get::<_, u32>("rounds")?,
+                    current_round: row.get::<_, u32>("current_round")?,
+                    min_participants: row.get::<_, u32>("min_participants")?,
+                    state: row.get::<_, PartyState>("state")?,
+                    ship_by: row.get::<_, Option<i64>>("ship_by")?,
+                })
+            },
+        )
+        .optional()
+    })
+    .await
+}
+
+#[poise::command(slash_command, identifying_name = "exclude_pair", ephemeral)]
+async fn exclude(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+    #[description = "One of the two people who must not draw each other"]
+    first: serenity_prelude::User,
+    #[description = "The other person"] second: serenity_prelude::User,
+) -> AppResult {
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.reply("Incorrect join phrase").await?;
+        return Ok(());
+    };
+    let Some(party) = find_party(&ctx.data.db, party_id.to_string()).await? else {
+        ctx.reply("No party exists with that join phrase!").await?;
+        return Ok(());
+    };
+    if party.admin_id != ctx.author().id.get() {
+        ctx.reply(format!(
+            "Only the admin of {} can add exclusions",
+            party.party_name
+        ))
+        .await?;
+        return Ok(());
+    }
+    if party.state != PartyState::Open {
+        ctx.reply(format!(
+            "Exclusions can only be added while signups are open, and {} is {}",
+            party.party_name, party.state
+        ))
+        .await?;
+        return Ok(());
+    }
+    if first.id == second.id {
+        ctx.reply("Nobody can draw themselves anyway").await?;
+        return Ok(());
+    }
+    let (a, b) = (first.id.get(), second.id.get());
+    ctx.data
+        .db
+        .conn(move |dbc| {
+            dbc.execute(
+                "INSERT OR IGNORE INTO party_exclusions (party_id, user_a, user_b) VALUES (?1, ?2, ?3)",
+                params![party_id.to_string(), a.min(b), a.max(b)],
+            )
+        })
+        .await?;
+    ctx.reply(format!(
+        "<@{a}> and <@{b}> will not draw each other in {}",
+        party.party_name
+    ))
+    .await?;
+    Ok(())
+}
+#[poise::command(slash_command, identifying_name = "pin_pair", ephemeral)]
+async fn pin(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+    #[description = "The person giving the gift"] giver: serenity_prelude::User,
+    #[description = "The person they will give to"] receiver: serenity_prelude::User,
+) -> AppResult {
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.reply("Incorrect join phrase").await?;
+        return Ok(());
+    };
+    let Some(party) = find_party(&ctx.data.db, party_id.to_string()).await? else {
+        ctx.reply("No party exists with that join phrase!").await?;
+        return Ok(());
+    };
+    let party_name = party.party_name.clone();
+    if party.admin_id != ctx.author().id.get() {
+        ctx.reply(format!("Only the admin of {party_name} can pin pairs"))
+            .await?;
+        return Ok(());
+    }
+    if party.state != PartyState::Open {
+        ctx.reply(format!(
+            "Pairs can only be pinned while signups are open, and {party_name} is {}",
+            party.state
+        ))
+        .await?;
+        return Ok(());
+    }
+    if giver.id == receiver.id {
+        ctx.reply("Nobody can give to themselves").await?;
+        return Ok(());
+    }
+    let (giver, receiver) = (giver.id.get(), receiver.id.get());
+    // Try the draw with the new pin first, so a pin can't leave the rest of the party stuck
+    let problem = ctx
+        .data
+        .db
+        .conn(move |dbc| {
+            let mut input = draw::DrawInput::load(dbc, party)?;
+            if !input.teams.is_empty() {
+                return Ok(Some(
+                    "Pairs can't be pinned in a team-vs-team party".to_owned(),
+                ));
+            }
+            let joined = |uid| input.participants.iter().any(|user| user.uid == uid);
+            if !joined(giver) || !joined(receiver) {
+                return Ok(Some(
+                    "Both people need to join the party before they can be pinned".to_owned(),
+                ));
+            }
+            input.pins.push((giver, receiver));
+            let (ids, rules) = input.rules();
+            let mut rng = rand_chacha::ChaCha20Rng::from_os_rng();
+            if let Err(e) = matching::draw(&ids, &rules, &mut rng) {
+                return Ok(Some(format!("That pin can't be kept: {e}")));
+            }
+            dbc.execute(
+                "INSERT OR IGNORE INTO party_pins (party_id, giver_id, receiver_id) VALUES (?1, ?2, ?3)",
+                params![party_id.to_string(), giver, receiver],
+            )?;
+            Ok(None)
+        })
+        .await?;
+    if let Some(problem) = problem {
+        ctx.reply(problem).await?;
+        return Ok(());
+    }
+    ctx.reply(format!(
+        "<@{giver}> will give to <@{receiver}> in {party_name}"
+    ))
+    .await?;
+    Ok(())
+}
+/// How many trial draws `/party preview` runs
+const PREVIEW_RUNS: usize = 200;
+
+#[poise::command(slash_command, identifying_name = "preview_party", ephemeral)]
+async fn preview(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+) -> AppResult {
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.reply("Incorrect join phrase").await?;
+        return Ok(());
+    };
+    let Some(party) = find_party(&ctx.data.db, party_id.to_string()).await? else {
+        ctx.reply("No party exists with that join phrase!").await?;
+        return Ok(());
+    };
+    if party.admin_id != ctx.author().id.get() {
+        ctx.reply(format!(
+            "Only the admin of {} can preview the draw",
+            party.party_name
+        ))
+        .await?;
+        return Ok(());
+    }
+    let input = ctx
+        .data
+        .db
+        .conn(move |dbc| draw::DrawInput::load(dbc, party))
+        .await?;
+    let (ids, rules) = input.rules();
+    // Nothing from the trial draws is stored or shown, only how they turned out
+    let stats = matching::preview(
+        &ids,
+        &rules,
+        PREVIEW_RUNS,
+        &mut rand_chacha::ChaCha20Rng::from_os_rng(),
+    );
+    let mut lines = vec![format!(
+        "**{}** has {} people signed up.",
+        input.party.party_name,
+        input.participants.len()
+    )];
+    match stats {
+        Err(e) => lines.push(format!("The draw can't be made right now: {e}.")),
+        Ok(stats) => {
+            lines.push(format!("All {} trial draws worked out.", stats.runs));
+            if stats.most_loops > 0 {
+                lines.push(format!(
+                    "They made {:.1} gift loops on average ({} to {}), and {} of them made a single loop.",
+                    stats.total_loops as f64 / stats.runs as f64,
+                    stats.fewest_loops,
+                    stats.most_loops,
+                    stats.single_loop_runs
+                ));
+            }
+            if rules.history_len() > 0 {
+                lines.push(format!(
+                    "Every trial avoided repeats from at least {} of {} earlier parties.",
+                    stats.fewest_history_honored,
+                    rules.history_len()
+                ));
+            }
+            if rules.ignores_interests(ids.len()) {
+                lines.push(format!(
+                    "Interests only steer draws of up to {} people, so this one ignores them.",
+                    matching::PREFERENCE_LIMIT
+                ));
+            }
+        }
+    }
+    ctx.reply(lines.join("\n")).await?;
+    Ok(())
+}
+#[poise::command(slash_command, identifying_name = "redraw_party", ephemeral)]
+async fn redraw(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+) -> AppResult {
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.reply("Incorrect join phrase").await?;
+        return Ok(());
+    };
+    let party_id = party_id.to_string();
+    let Some(party) = find_party(&ctx.data.db, party_id.clone()).await? else {
+        ctx.reply("No party exists with that join phrase!").await?;
+        return Ok(());
+    };
+    let admin_id = ctx.author().id.get();
+    if party.admin_id != admin_id {
+        ctx.reply(format!(
+            "Only the admin of {} can redo the draw",
+            party.party_name
+        ))
+        .await?;
+        return Ok(());
+    }
+    // Once the seed is out the pairs are final. A closed party is one whose first draw
+    // couldn't be made, so redrawing it makes that draw.
+    let first_draw = party.state == PartyState::Closed;
+    if !matches!(party.state, PartyState::Closed | PartyState::Drawn) || party.white_elephant {
+        ctx.reply(format!(
+            "Only closed or drawn parties can be redrawn, and {} is {}",
+            party.party_name, party.state
+        ))
+        .await?;
+        return Ok(());
+    }
+    let input = ctx
+        .data
+        .db
+        .conn(move |dbc| {
+            if draw::is_drawn(dbc, &party.id)? == first_draw {
+                return Ok(None);
+            }
+            draw::DrawInput::load(dbc, party).map(Some)
+        })
+        .await?;
+    let Some(input) = input else {
+        ctx.reply(if first_draw {
+            "That party was drawn in the meantime"
+        } else {
+            "That party hasn't been drawn yet"
+        })
+        .await?;
+        return Ok(());
+    };
+    let (ids, rules) = input.rules();
+    let seed = rand_chacha::ChaCha20Rng::from_os_rng().next_u64();
+    let assignment = match matching::draw(
+        &ids,
+        &rules,
+        &mut rand_chacha::ChaCha20Rng::seed_from_u64(seed),
+    ) {
+        Ok(assignment) => assignment,
+        Err(e) => {
+            ctx.reply(format!("The draw could not be redone: {e}"))
+                .await?;
+            return Ok(());
+        }
+    };
+    let (dump, rows) = (input.dump(), input.rows(&assignment));
+    if first_draw {
+        let (draw_id, ends_at, rounds) =
+            (party_id.clone(), input.party.ends_at, input.party.rounds);
+        let now = chrono::Utc::now().timestamp();
+        let commitment = ctx
+            .data
+            .db
+            .conn_mut(move |dbc| {
+                let commitment = draw::record_draw(dbc, &draw_id, &dump, &rows, seed)?;
+                if commitment.is_some() {
+                    scheduler::catch_up_rounds(dbc, &draw_id, ends_at, rounds, now)?;
+                }
+                Ok(commitment)
+            })
+            .await?;
+        let Some(commitment) = commitment else {
+            ctx.reply(format!(
+                "{} was drawn in the meantime, so this draw was dropped",
+                input.party.party_name
+            ))
+            .await?;
+            return Ok(());
+        };
+        announce_commitment(ctx.http(), &input.party, &commitment).await;
+        event!(Level::INFO, "Party {party_id} was drawn by {admin_id}");
+        ctx.reply(format!(
+            "{} has been drawn, everyone can now use /get_my_target",
+            input.party.party_name
+        ))
+        .await?;
+        return Ok(());
+    }
+    let redrawn = {
+        let party_id = party_id.clone();
+        let now = chrono::Utc::now().timestamp();
+        ctx.data
+            .db
+            .conn_mut(move |dbc| {
+                draw::redraw_matches(dbc, &party_id, &dump, &rows, admin_id, now, seed)
+            })
+            .await?
+    };
+    let Some((changed, commitment)) = redrawn else {
+        ctx.reply(format!(
+            "{} was revealed in the meantime, so its draw can't be redone",
+            input.party.party_name
+        ))
+        .await?;
+        return Ok(());
+    };
+    announce_commitment(ctx.http(), &input.party, &commitment).await;
+    event!(
+        Level::INFO,
+        "Party {party_id} was redrawn by {admin_id}, changing {} givers",
+        changed.len()
+    );
+    for giver in &changed {
+        let dm = serenity_prelude::UserId::new(*giver)
+            .direct_message(
+                ctx.http(),
+                CreateMessage::new().content(format!(
+                    "The draw for {} was redone and your target has changed. Use /get_my_target to see who you're gifting now.",
+                    input.party.party_name
+                )),
+            )
+            .await;
+        if let Err(e) = dm {
+            event!(Level::WARN, "Could not tell {giver} about the redraw: {e}");
+        }
+    }
+    ctx.reply(format!(
+        "{} has been redrawn and {} people were told their target changed",
+        input.party.party_name,
+        changed.len()
+    ))
+    .await?;
+    Ok(())
+}
+#[poise::command(slash_command, identifying_name = "admit_participant", ephemeral)]
+async fn admit(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+    #[description = "The person allowed to join after signups closed"] user: serenity_prelude::User,
+) -> AppResult {
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.reply("Incorrect join phrase").await?;
+        return Ok(());
+    };
+    let Some(party) = find_party(&ctx.data.db, party_id.to_string()).await? else {
+        ctx.reply("No party exists with that join phrase!").await?;
+        return Ok(());
+    };
+    if party.admin_id != ctx.author().id.get() {
+        ctx.reply(format!(
+            "Only the admin of {} can let people in late",
+            party.party_name
+        ))
+        .await?;
+        return Ok(());
+    }
+    if !party.state.is_running() {
+        ctx.reply(format!(
+            "{} is {}, so nobody can join it anymore",
+            party.party_name, party.state
+        ))
+        .await?;
+        return Ok(());
+    }
+    if party.white_elephant {
+        let party_id = party.id.clone();
+        let started = ctx
+            .data
+            .db
+            .conn(move |dbc| elephant::load(dbc, &party_id))
+            .await?
+            .is_some();
+        if started {
+            ctx.reply(format!(
+                "The white elephant game for {} has started, so there is no turn left for anyone new",
+                party.party_name
+            ))
+            .await?;
+            return Ok(());
+        }
+    }
+    let uid = user.id.get();
+    ctx.data
+        .db
+        .conn(move |dbc| {
+            dbc.execute(
+                "INSERT OR IGNORE INTO party_admissions (party_id, uid) VALUES (?1, ?2)",
+                params![party_id.to_string(), uid],
+            )
+        })
+        .await?;
+    ctx.reply(format!(
+        "<@{uid}> can now join {} with /party join. If it has been drawn already, they will be slotted in without reshuffling anyone else.",
+        party.party_name
+    ))
+    .await?;
+    Ok(())
+}
+
+#[poise::command(slash_command, identifying_name = "leave_party", ephemeral)]
+async fn leave(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+) -> AppResult {
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.reply("Incorrect join phrase").await?;
+        return Ok(());
+    };
+    let Some(party) = find_party(&ctx.data.db, party_id.to_string()).await? else {
+        ctx.reply("No party exists with that join phrase!").await?;
+        return Ok(());
+    };
+    let reply = take_out(ctx, party, ctx.author().id.get(), false).await?;
+    ctx.reply(reply).await?;
+    Ok(())
+}
+
+#[poise::command(slash_command, identifying_name = "remove_participant", ephemeral)]
+async fn remove(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+    #[description = "The person to take out of the party"] user: serenity_prelude::User,
+    #[description = "Remove them even if the party then has to be redrawn"] force: Option<bool>,
+) -> AppResult {
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.reply("Incorrect join phrase").await?;
+        return Ok(());
+    };
+    let Some(party) = find_party(&ctx.data.db, party_id.to_string()).await? else {
+        ctx.reply("No party exists with that join phrase!").await?;
+        return Ok(());
+    };
+    if party.admin_id != ctx.author().id.get() {
+        ctx.reply(format!(
+            "Only the admin of {} can remove people",
+            party.party_name
+        ))
+        .await?;
+        return Ok(());
+    }
+    let reply = take_out(ctx, party, user.id.get(), force.unwrap_or(false)).await?;
+    ctx.reply(reply).await?;
+    Ok(())
+}
+
+/// Takes `leaver` out of `party` and tells whoever inherited their receivers.
+/// Returns the reply for whoever asked.
+async fn take_out(
+    ctx: AppContext<'_>,
+    party: PartyRecord,
+    leaver: u64,
+    force: bool,
+) -> Result<String, AppErr> {
+    let party_id = party.id.clone();
+    let party_name = party.party_name.clone();
+    if !party.state.is_running() {
+        return Ok(format!(
+            "{party_name} is {}, so nobody can leave it anymore",
+            party.state
+        ));
+    }
+    if party.white_elephant {
+        let party_id = party_id.clone();
+        let started = ctx
+            .data
+            .db
+            .conn(move |dbc| elephant::load(dbc, &party_id))
+            .await?
+            .is_some();
+        if started {
+            return Ok(format!(
+                "The white elephant game for {party_name} has started, so everyone's gift is already in play"
+            ));
+        }
+    }
+    let departure = ctx
+        .data
+        .db
+        .conn_mut(move |dbc| draw::leave(dbc, party, leaver, force))
+        .await?;
+    let givers = match departure {
+        draw::Departure::NotJoined => {
+            return Ok(format!("<@{leaver}> is not part of {party_name}"));
+        }
+        draw::Departure::Stuck(e) if force => {
+            event!(Level::INFO, "{leaver} was forced out of party {party_id}");
+            return Ok(format!(
+                "<@{leaver}> was removed from {party_name}, but {e}. Use /party redraw to finish the job."
+            ));
+        }
+        draw::Departure::Stuck(e) => {
+            return Ok(format!(
+                "<@{leaver}> can't leave {party_name} without a full redraw: {e}. The admin can remove them with `force` and then use /party redraw."
+            ));
+        }
+        draw::Departure::Removed => Vec::new(),
+        draw::Departure::Spliced(givers) => givers,
+    };
+    event!(
+        Level::INFO,
+        "{leaver} left party {party_id}, changing {} givers",
+        givers.len()
+    );
+    for giver in &givers {
+        let dm = serenity_prelude::UserId::new(*giver)
+            .direct_message(
+                ctx.http(),
+                CreateMessage::new().content(format!(
+                    "Someone left {party_name}, so your target has changed. Use /get_my_target to see who you're gifting now."
+                )),
+            )
+            .await;
+        if let Err(e) = dm {
+            event!(
+                Level::WARN,
+                "Could not tell {giver} about their new target: {e}"
+            );
+        }
+    }
+    Ok(format!("<@{leaver}> is no longer part of {party_name}"))
+}
+/// Publishes the seed a party was drawn with, so anyone can check it against the commitment
+#[poise::command(slash_command, identifying_name = "reveal_party")]
+async fn reveal(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+) -> AppResult {
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.reply("Incorrect join phrase").await?;
+        return Ok(());
+    };
+    let Some(party) = find_party(&ctx.data.db, party_id.to_string()).await? else {
+        ctx.reply("No party exists with that join phrase!").await?;
+        return Ok(());
+    };
+    if party.admin_id != ctx.author().id.get() {
+        ctx.reply(format!(
+            "Only the admin of {} can reveal the draw",
+            party.party_name
+        ))
+        .await?;
+        return Ok(());
+    }
+    // A revealed party may still be waiting for its seals to get out
+    if !matches!(party.state, PartyState::Drawn | PartyState::Revealed)
+        || party.current_round < party.rounds
+    {
+        ctx.reply(format!(
+            "Only parties that have drawn every round can be revealed, and {} is {}",
+            party.party_name, party.state
+        ))
+        .await?;
+        return Ok(());
+    }
+    let Some((content, batches)) = open_seal(&ctx.data.db, &party).await? else {
+        ctx.reply(format!(
+            "{} has no sealed draw left to reveal",
+            party.party_name
+        ))
+        .await?;
+        return Ok(());
+    };
+    for (batch, files) in batches.into_iter().enumerate() {
+        let reply = CreateReply {
+            attachments: files,
+            ..Default::default()
+        };
+        ctx.send(if batch == 0 {
+            reply.content(content.clone())
+        } else {
+            reply
+        })
+        .await?;
+    }
+    ctx.data
+        .db
+        .conn(move |dbc| draw::finish_reveal(dbc, &party.id))
+        .await?;
+    Ok(())
+}
+
+/// Discord allows at most this many files on one message
+const ATTACHMENTS_PER_MESSAGE: usize = 10;
+
+/// Marks a drawn party as revealed. Returns the announcement and the sealed file of every round,
+/// split into batches that fit on one message each, or `None` if the party has no sealed draw
+/// or was revealed in the meantime. Once every batch is out, [`draw::finish_reveal`] has to be
+/// called, until then the files can be opened again.
+async fn open_seal(
+    db: &Pool,
+    party: &PartyRecord,
+) -> Result<Option<(String, Vec<Vec<serenity_prelude::CreateAttachment>>)>, async_sqlite::Error> {
+    let party_id = party.id.clone();
+    let sealed = db
+        .conn(move |dbc| {
+            let seals = draw::sealed_rounds(dbc, &party_id)?;
+            if seals.is_empty() {
+                return Ok(None);
+            }
+            let current = draw::current_record(dbc, &party_id)?;
+            // Revealing twice at once must not publish the seed of a redraw
+            if !draw::start_reveal(dbc, &party_id)? {
+                return Ok(None);
+            }
+            Ok(Some((seals, current)))
+        })
+        .await?;
+    let Some((seals, current)) = sealed else {
+        return Ok(None);
+    };
+    let replayable = seals.iter().all(|sealed| sealed.input.is_some());
+    let mut files = Vec::new();
+    let mut content = match seals.as_slice() {
+        [sealed] if party.rounds == 1 => {
+            files.push(("draw.txt".to_owned(), sealed.text()));
+            let mut content = format!(
+                "**{}** was drawn with the seed `{}`. Its commitment was `{}`, the sha256 of the seed, a `:` and the attached file.",
+                party.party_name, sealed.seed, sealed.commitment
+            );
+            if replayable {
+                content.push_str(&format!(
+                    " The file holds everything the draw ran on, so `secretsatan replay draw.txt {}` redoes it from scratch.",
+                    sealed.seed
+                ));
+            }
+            content
+        }
+        // Every day was committed to on its own, and the seeds would not fit in one message
+        seals => {
+            let mut seeds = String::new();
+            for sealed in seals {
+                seeds.push_str(&format!(
+                    "day {} seed {} commitment {}\n",
+                    sealed.round, sealed.seed, sealed.commitment
+                ));
+                files.push((format!("day-{}.txt", sealed.round), sealed.text()));
+            }
+            files.insert(0, ("seeds.txt".to_owned(), seeds));
+            let mut content = format!(
+                "**{}** was drawn once a day. `seeds.txt` lists each day's seed and the commitment announced for it, the sha256 of the seed, a `:` and that day's attached file.",
+                party.party_name
+            );
+            if replayable {
+                content.push_str(" Each file holds everything that day's draw ran on, so `secretsatan replay day-1.txt <seed>` redoes it from scratch.");
+            }
+            content
+        }
+    };
+    if seals.last().is_some_and(|sealed| current != sealed.record) {
+        content.push_str("\nSome pairs have changed since, because people left or joined late.");
+    }
+    let batches = files
+        .chunks(ATTACHMENTS_PER_MESSAGE)
+        .map(|batch| {
+            batch
+                .iter()
+                .map(|(name, text)| {
+                    serenity_prelude::CreateAttachment::bytes(text.clone(), name.clone())
+                })
+                .collect()
+        })
+        .collect();
+    Ok(Some((content, batches)))
+}
+
+/// Lists everything the scheduler has planned or done for a party
+#[poise::command(slash_command, identifying_name = "party_schedule", ephemeral)]
+async fn schedule(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+) -> AppResult {
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.reply("Incorrect join phrase").await?;
+        return Ok(());
+    };
+    let Some(party) = find_party(&ctx.data.db, party_id.to_string()).await? else {
+        ctx.reply("No party exists with that join phrase!").await?;
+        return Ok(());
+    };
+    if party.admin_id != ctx.author().id.get() {
+        ctx.reply(format!(
+            "Only the admin of {} can see its schedule",
+            party.party_name
+        ))
+        .await?;
+        return Ok(());
+    }
+    let jobs = ctx
+        .data
+        .db
+        .conn(move |dbc| scheduler::list(dbc, &party_id.to_string()))
+        .await?;
+    let mut lines = vec![format!(
+        "**{}** is {}, and its signups close <t:{}:R>.",
+        party.party_name, party.state, party.ends_at
+    )];
+    for job in jobs {
+        let mut line = format!("- {} <t:{}:f>: {}", job.kind, job.due_at, job.status);
+        if job.attempts > 1 || job.last_error.is_some() {
+            line.push_str(&format!(" after {} attempts", job.attempts));
+        }
+        if let Some(error) = job.last_error {
+            line.push_str(&format!(", last failing with `{error}`"));
+        }
+        lines.push(line);
+    }
+    ctx.reply(lines.join("\n")).await?;
+    Ok(())
+}
+
+/// Redoes a draw from the file published at its reveal and its seed, and checks both against
+/// the commitment that was announced when the party was drawn
+fn replay(path: &str, seed: &str, commitment: Option<&str>) -> Result<()> {
+    let text = std::fs::read_to_string(path)?;
+    let seed = seed.parse::<u64>()?;
+    let computed = audit::commitment(seed, &text);
+    match commitment {
+        Some(commitment) => println!(
+            "The file and seed {} the commitment {commitment}",
+            if computed == commitment.trim() {
+                "match"
+            } else {
+                "DO NOT match"
+            }
+        ),
+        None => println!("The file and seed hash to the commitment {computed}"),
+    }
+    let (input, record) =
+        audit::split_sealed(&text).ok_or(eyre!("{path} doesn't end with a record line"))?;
+    let input = draw::DrawInput::parse(input)?;
+    let (ids, rules) = input.rules();
+    let assignment = matching::draw(
+        &ids,
+        &rules,
+        &mut rand_chacha::ChaCha20Rng::seed_from_u64(seed),
+    )?;
+    let replayed = audit::canonical(
+        input
+            .rows(&assignment)
+            .iter()
+            .map(|row| (row.giver_id, row.receiver_id)),
+    );
+    if replayed == record {
+        println!("Replaying seed {seed} on the file's input gives its record");
+    } else {
+        println!("Replaying seed {seed} on the file's input gives a DIFFERENT record");
+    }
+    Ok(())
+}
+#[derive(poise::ChoiceParameter, Debug, Clone, Copy)]
+enum BudgetTier {
+    #[name = "$10"]
+    Ten,
+    #[name = "$25"]
+    TwentyFive,
+    #[name = "$50"]
+    Fifty,
+}
+impl BudgetTier {
+    fn dollars(self) -> u32 {
+        match self {
+            BudgetTier::Ten => 10,
+            BudgetTier::TwentyFive => 25,
+            BudgetTier::Fifty => 50,
+        }
+    }
+}
 #[derive(poise::Modal, Debug)]
 #[name = "Signup"]
 struct JoinPartyForm {
@@ -79,43 +961,101 @@ struct JoinPartyForm {
     #[placeholder = "Anything you want the person matched with you to know"]
     #[paragraph]
     user_hints: String,
+    #[name = "Region"]
+    #[placeholder = "Where you can send and receive gifts, like Europe"]
+    user_region: Option<String>,
 }
 #[poise::command(slash_command, identifying_name = "join_party", ephemeral)]
 async fn join(
     ctx: AppContext<'_>,
     #[description = "The join phrase for the party"] joinphrase: String,
+    #[description = "The team you are joining, for team-vs-team parties"] team: Option<String>,
+    #[description = "Whether you can ship gifts outside your region"] ships_abroad: Option<bool>,
+    #[description = "How much you want to spend, for parties with budget tiers"] budget: Option<
+        BudgetTier,
+    >,
+    #[description = "Comma separated interests, like books, games, coffee"] interests: Option<
+        String,
+    >,
 ) -> AppResult {
-    let rng_seed = {
-        let mut v = Vec::default();
-        match mnemonic::decode(joinphrase, &mut v) {
-            Ok(_) => {}
-            Err(_) => {
-                ctx.reply("Incorrect join phrase").await?;
-                return Ok(());
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.reply("Incorrect join phrase").await?;
+        return Ok(());
+    };
+    let teams = ctx
+        .data
+        .db
+        .conn(move |dbc| draw::party_teams(dbc, &party_id.to_string()))
+        .await?;
+    let team_id = match (team, teams.is_empty()) {
+        (None, true) => None,
+        (Some(team), false) => {
+            match teams
+                .iter()
+                .find(|(_, team_name)| team_name.eq_ignore_ascii_case(team.trim()))
+            {
+                Some((team_id, _)) => Some(*team_id),
+                None => {
+                    ctx.reply(format!(
+                        "There is no team called {team}. Pick one of: {}",
+                        teams
+                            .iter()
+                            .map(|(_, team_name)| team_name.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                    .await?;
+                    return Ok(());
+                }
             }
-        };
-        if v.len() != 4 {
-            ctx.reply("Incorrect join phrase").await?;
+        }
+        (None, false) => {
+            ctx.reply(format!(
+                "This party is played in teams. Pick one of: {}",
+                teams
+                    .iter()
+                    .map(|(_, team_name)| team_name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+            .await?;
+            return Ok(());
+        }
+        (Some(_), true) => {
+            ctx.reply("This party is not played in teams").await?;
             return Ok(());
         }
-        u32::from_be_bytes([v[0], v[1], v[2], v[3]])
     };
-    let party_id = uuid::Builder::from_random_bytes({
-        let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(rng_seed as u64);
-        let mut v: [u8; 16] = [0; 16];
-        rng.fill_bytes(&mut v);
-        v
-    })
-    .into_uuid();
+    // Givers who share interests with their receiver are preferred in the draw
+    let interests = interests
+        .map(|interests| {
+            interests
+                .split(',')
+                .map(|tag| tag.trim().to_lowercase())
+                .filter(|tag| !tag.is_empty())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .filter(|interests| !interests.is_empty());
+    let uid = ctx.author().id.get();
     let party_status = ctx
         .data
         .db
         .conn(move |dbc| {
+            // The admin can let people in after signups close
+            let admitted = dbc
+                .prepare("SELECT 1 FROM party_admissions WHERE party_id = ?1 AND uid = ?2")?
+                .exists(params![party_id.to_string(), uid])?;
+            // A white elephant game that has started has no turn left for anyone new
             dbc.query_one(
-                &format!("SELECT * FROM party_info WHERE id = \"{party_id}\";"),
-                [],
+                "SELECT party_name, state, white_elephant AND EXISTS (
+                    SELECT 1 FROM elephant_games WHERE party_id = parties.id
+                ) AS started FROM parties WHERE id = ?1",
+                [party_id.to_string()],
                 |row| {
-                    if row.get::<&str, i64>("ends_at")? <= chrono::Utc::now().timestamp() {
+                    let state = row.get::<&str, PartyState>("state")?;
+                    let started = row.get::<&str, bool>("started")?;
+                    if started || (state != PartyState::Open && !(admitted && state.is_running())) {
                         return Ok(Err(row.get::<&str, String>("party_name")?));
                     }
                     Ok(Ok(row.get::<&str, String>("party_name")?))
@@ -173,44 +1113,142 @@ async fn join(
                             .await?;
                         match form_response {
                             Some(response) => {
+                                let region = response
+                                    .user_region
+                                    .map(|region| region.trim().to_owned())
+                                    .filter(|region| !region.is_empty());
                                 let (user_name, user_hints) = (
                                     base64::prelude::BASE64_STANDARD.encode(response.user_fullname),
                                     base64::prelude::BASE64_STANDARD.encode(response.user_hints),
                                 );
-                                let uname_handle = user_name.clone();
-                                let uid = ctx.author().id.get();
-                                let db_response = ctx.data()
-                                        .db
-                                        .conn(move |dbc| {
-                                            dbc.execute(
-                                                &format!(
-                                                    "INSERT INTO \"{party_id}\" (uid, name, hint) VALUES (?1, ?2, ?3);"
-                                                ),
-                                                params![uid, uname_handle, user_hints],
+                                let Some(party) =
+                                    find_party(&ctx.data.db, party_id.to_string()).await?
+                                else {
+                                    ctx.reply("No party exists with that join phrase!").await?;
+                                    return Ok(());
+                                };
+                                if party.regional && region.is_none() {
+                                    reply_handle
+                                        .edit(
+                                            Context::Application(ctx),
+                                            CreateReply {
+                                                content: Some(format!("Failed to join: {party_name} only matches people within a region, so please fill in yours.")),
+                                                components: Some(vec![]),
+                                                ..Default::default()
+                                            },
+                                        )
+                                        .await?;
+                                    return Ok(());
+                                }
+                                if party.budget_tiers != budget.is_some() {
+                                    let problem = if party.budget_tiers {
+                                        "please pick a budget"
+                                    } else {
+                                        "it has no budget tiers"
+                                    };
+                                    reply_handle
+                                        .edit(
+                                            Context::Application(ctx),
+                                            CreateReply {
+                                                content: Some(format!(
+                                                    "Failed to join {party_name}: {problem}."
+                                                )),
+                                                components: Some(vec![]),
+                                                ..Default::default()
+                                            },
+                                        )
+                                        .await?;
+                                    return Ok(());
+                                }
+                                let newcomer = draw::Participant {
+                                    uid,
+                                    name: user_name,
+                                    hint: user_hints,
+                                    team_id,
+                                    region,
+                                    ships_abroad: ships_abroad.unwrap_or(false),
+                                    budget: budget.map(BudgetTier::dollars),
+                                    interests,
+                                };
+                                let db_response = ctx
+                                    .data()
+                                    .db
+                                    .conn_mut(move |dbc| {
+                                        draw::join(
+                                            dbc,
+                                            party,
+                                            newcomer,
+                                            &mut rand_chacha::ChaCha20Rng::from_os_rng(),
+                                        )
+                                    })
+                                    .await;
+                                if let Err(async_sqlite::Error::Rusqlite(rusqlite_err)) =
+                                    &db_response
+                                    && rusqlite_err.sqlite_error_code()
+                                        == Some(
+                                            async_sqlite::rusqlite::ErrorCode::ConstraintViolation,
+                                        )
+                                {
+                                    reply_handle.edit(
+                                        Context::Application(ctx),
+                                        CreateReply {
+                                            content: Some("Failed to join: You are already in this party!".to_owned()),
+                                            components: Some(vec![]),
+                                            ..Default::default()
+                                    }).await?;
+                                    return Ok(());
+                                }
+                                let givers = match db_response? {
+                                    draw::Arrival::Joined => Vec::new(),
+                                    draw::Arrival::Inserted(givers) => givers,
+                                    draw::Arrival::GameStarted => {
+                                        reply_handle
+                                            .edit(
+                                                Context::Application(ctx),
+                                                CreateReply {
+                                                    content: Some(format!("Failed to join: the white elephant game for {party_name} has started, so there is no turn left for you.")),
+                                                    components: Some(vec![]),
+                                                    ..Default::default()
+                                                },
+                                            )
+                                            .await?;
+                                        return Ok(());
+                                    }
+                                    draw::Arrival::Stuck(e) => {
+                                        reply_handle
+                                            .edit(
+                                                Context::Application(ctx),
+                                                CreateReply {
+                                                    content: Some(format!("Failed to join: {party_name} has already been drawn and {e}. Ask the admin to redraw it.")),
+                                                    components: Some(vec![]),
+                                                    ..Default::default()
+                                                },
                                             )
-                                        })
+                                            .await?;
+                                        return Ok(());
+                                    }
+                                };
+                                if !givers.is_empty() {
+                                    event!(
+                                        Level::INFO,
+                                        "{uid} joined drawn party {party_id}, changing {} givers",
+                                        givers.len()
+                                    );
+                                }
+                                for giver in &givers {
+                                    let dm = serenity_prelude::UserId::new(*giver)
+                                        .direct_message(
+                                            ctx.http(),
+                                            CreateMessage::new().content(format!(
+                                                "Someone joined {party_name} late, so your target has changed. Use /get_my_target to see who you're gifting now."
+                                            )),
+                                        )
                                         .await;
-                                if let Err(e) = db_response {
-                                    match e {
-                                        async_sqlite::Error::Rusqlite(rusqlite_err) => {
-                                            if let Some(errcode) = rusqlite_err.sqlite_error_code()
-                                            {
-                                                match errcode {
-                                                    async_sqlite::rusqlite::ErrorCode::ConstraintViolation => {
-                                                        reply_handle.edit(
-                                                            Context::Application(ctx),
-                                                            CreateReply {
-                                                                content: Some("Failed to join: You are already in this party!".to_owned()),
-                                                                components: Some(vec![]),
-                                                                ..Default::default()
-                                                        }).await?;
-                                                        return Ok(());
-                                                    },
-                                                    _ => {}
-                                                }
-                                            }
-                                        }
-                                        _ => {}
+                                    if let Err(e) = dm {
+                                        event!(
+                                            Level::WARN,
+                                            "Could not tell {giver} about their new target: {e}"
+                                        );
                                     }
                                 }
                                 reply_handle
@@ -257,200 +1295,771 @@ async fn join(
                     }
                     _ => unreachable!("undefined button"),
                 },
-                None => {
-                    ctx.say("Join command timed out, please try again").await?;
+                None => {
+                    ctx.say("Join command timed out, please try again").await?;
+                }
+            }
+        }
+        Err(_e) => {
+            ctx.reply("No party exists with that join phrase!").await?;
+            return Ok(());
+        }
+    };
+    Ok(())
+}
+
+#[poise::command(slash_command, identifying_name = "create_party", ephemeral)]
+#[allow(clippy::too_many_arguments)]
+async fn create(
+    ctx: AppContext<'_>,
+    #[description = "How long to allow users to join this party"] signup_duration: String,
+    #[description = "The public name of this party"] party_name: String,
+    #[description = "Join phrase of an earlier party whose pairings should not repeat"]
+    follows: Option<String>,
+    #[description = "How many earlier parties back to avoid repeats from (default 1)"]
+    #[min = 1]
+    #[max = 10]
+    history_depth: Option<u32>,
+    #[description = "Make all gifts form one big circle instead of several small ones"]
+    single_loop: Option<bool>,
+    #[description = "How many people everyone gives a gift to (default 1)"]
+    #[min = 1]
+    #[max = 5]
+    gifts_per_person: Option<u32>,
+    #[description = "Comma separated team names to gift team-vs-team instead of person-to-person"]
+    teams: Option<String>,
+    #[description = "Only match givers with receivers in their shipping region"] regional: Option<
+        bool,
+    >,
+    #[description = "Have everyone pick a $10, $25 or $50 budget and only match equal budgets"]
+    budget_tiers: Option<bool>,
+    #[description = "Play a turn-based white elephant steal game instead of drawing pairs"]
+    white_elephant: Option<bool>,
+    #[description = "How often a white elephant gift can be stolen before it stays put (default 3)"]
+    #[min = 1]
+    #[max = 10]
+    steal_limit: Option<u32>,
+    #[description = "Draw a new round every day for this many days, like an advent calendar"]
+    #[min = 1]
+    #[max = 25]
+    rounds: Option<u32>,
+    #[description = "Cancel the party if fewer people than this sign up (default 2)"]
+    #[min = 2]
+    #[max = 100]
+    min_participants: Option<u32>,
+    #[description = "How long after signups close gifts have to be shipped"] ship_within: Option<
+        String,
+    >,
+    #[description = "How long after signups close the draw is revealed to everyone"]
+    reveal_after: Option<String>,
+) -> AppResult {
+    let (single_loop, gifts_per_person) =
+        (single_loop.unwrap_or(false), gifts_per_person.unwrap_or(1));
+    if single_loop && gifts_per_person > 1 {
+        ctx.reply("A single loop only works with one gift per person")
+            .await?;
+        return Ok(());
+    }
+    let regional = regional.unwrap_or(false);
+    let budget_tiers = budget_tiers.unwrap_or(false);
+    if (regional || budget_tiers) && teams.is_some() {
+        ctx.reply("Team parties can't be limited to regions or budgets")
+            .await?;
+        return Ok(());
+    }
+    let white_elephant = white_elephant.unwrap_or(false);
+    if white_elephant
+        && (single_loop || gifts_per_person > 1 || teams.is_some() || regional || budget_tiers)
+    {
+        ctx.reply("White elephant parties don't draw pairs, so they can't use loops, teams, regions, budgets or several gifts")
+            .await?;
+        return Ok(());
+    }
+    let rounds = rounds.unwrap_or(1);
+    if white_elephant && rounds > 1 {
+        ctx.reply("A white elephant game only has one round")
+            .await?;
+        return Ok(());
+    }
+    let mut team_names = Vec::<String>::new();
+    for team_name in teams.iter().flat_map(|teams| teams.split(',')) {
+        let team_name = team_name.trim();
+        if !team_name.is_empty()
+            && !team_names
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(team_name))
+        {
+            team_names.push(team_name.to_owned());
+        }
+    }
+    if teams.is_some() && team_names.len() <= gifts_per_person as usize {
+        ctx.reply(format!(
+            "A team party needs at least {} teams",
+            gifts_per_person + 1
+        ))
+        .await?;
+        return Ok(());
+    }
+    let follows_id = match follows {
+        Some(joinphrase) => {
+            let previous = party_id_from_phrase(joinphrase).map(|id| id.to_string());
+            match previous {
+                Some(previous) if find_party(&ctx.data.db, previous.clone()).await?.is_some() => {
+                    Some(previous)
+                }
+                _ => {
+                    ctx.reply("No earlier party exists with that join phrase!")
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+        None => None,
+    };
+    let rng_seed = rand::rng().random::<u32>();
+    let seedphrase = mnemonic::to_string(rng_seed.to_be_bytes());
+    let id = uuid::Builder::from_random_bytes({
+        let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(rng_seed as u64);
+        let mut v: [u8; 16] = [0; 16];
+        rng.fill_bytes(&mut v);
+        v
+    })
+    .into_uuid();
+
+    let signup_duration = duration_str::parse(&signup_duration).map_err(|e| eyre!("{e}"))?;
+    let now = chrono::Utc::now();
+    let ends_at = (now + signup_duration).timestamp();
+    // Both deadlines count from when signups close
+    let after_signups = |duration: Option<String>| -> Result<Option<i64>> {
+        duration
+            .map(|duration| {
+                let duration = duration_str::parse(&duration).map_err(|e| eyre!("{e}"))?;
+                Ok(ends_at + i64::try_from(duration.as_secs())?)
+            })
+            .transpose()
+    };
+    let ship_by = after_signups(ship_within)?;
+    let reveal_at = after_signups(reveal_after)?;
+    if white_elephant && (ship_by.is_some() || reveal_at.is_some()) {
+        ctx.reply("White elephant gifts are swapped live, so there is nothing to ship or reveal")
+            .await?;
+        return Ok(());
+    }
+    if let (Some(ship_by), Some(reveal_at)) = (ship_by, reveal_at)
+        && reveal_at < ship_by
+    {
+        ctx.reply("The reveal can't come before the shipping deadline")
+            .await?;
+        return Ok(());
+    }
+    if let Some(reveal_at) = reveal_at
+        && reveal_at < ends_at + i64::from(rounds - 1) * scheduler::ROUND_LENGTH
+    {
+        ctx.reply("The reveal can't come before the last round is drawn")
+            .await?;
+        return Ok(());
+    }
+
+    ctx.data()
+        .db
+        .conn(move |dbc| {
+            for (team_id, team_name) in team_names.iter().enumerate() {
+                dbc.execute(
+                    "INSERT INTO party_teams (party_id, team_id, team_name) VALUES (?1, ?2, ?3)",
+                    params![id.to_string(), team_id + 1, team_name],
+                )?;
+            }
+            Ok(())
+        })
+        .await?;
+    let author_id_handle = ctx.author().id.get();
+    let channel_id = ctx.channel_id().get();
+    ctx.data()
+        .db
+        .conn(move |dbc| {
+            match dbc.execute(
+            "INSERT INTO parties (id, admin_id, party_name, started_at, ends_at, follows_id, history_depth, single_loop, gifts_per_person, channel_id, regional, budget_tiers, white_elephant, steal_limit, rounds, min_participants, ship_by, reveal_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+            params![
+                id.to_string(),
+                author_id_handle,
+                party_name,
+                now.timestamp(),
+                ends_at,
+                follows_id,
+                history_depth.unwrap_or(1),
+                single_loop,
+                gifts_per_person,
+                channel_id,
+                regional,
+                budget_tiers,
+                white_elephant,
+                steal_limit.unwrap_or(3),
+                rounds,
+                min_participants.unwrap_or(2),
+                ship_by,
+                reveal_at
+            ]) {
+                Ok(_) => {},
+                Err(e) => {event!(Level::WARN, "SQL error: {e}"); return Err(e)}
+            }
+            for (kind, due_at) in scheduler::party_jobs(ends_at, rounds, ship_by, reveal_at) {
+                scheduler::enqueue(dbc, &id.to_string(), kind, due_at)?;
+            }
+            Ok(())
+        })
+        .await?;
+    event!(
+        Level::INFO,
+        "Created a party with the seed phrase {seedphrase} and the uuid {id}"
+    );
+    ctx.reply(format!("Created a new party with the join phrase `{seedphrase}`. Don't forget to join your own party!")).await?;
+    Ok(())
+}
+
+/// Closes signups for a party, draws its pairings and writes them to its assignments.
+///
+/// If no valid assignment exists the party admin is told why instead.
+async fn run_draw(db: Pool, http: Arc<serenity_prelude::Http>, party_id: String) -> Result<()> {
+    let party = find_party(&db, party_id.clone())
+        .await?
+        .ok_or(eyre!("Party {party_id} does not exist"))?;
+    let (party_id_handle, state) = (party_id.clone(), party.state);
+    let signed_up = db
+        .conn(move |dbc| {
+            // A party that is already closed is a draw being retried
+            if state != PartyState::Closed
+                && !lifecycle::transition(dbc, &party_id_handle, PartyState::Closed)?
+            {
+                return Ok(None);
+            }
+            draw::signed_up(dbc, &party_id_handle).map(Some)
+        })
+        .await?;
+    let Some(signed_up) = signed_up else {
+        event!(
+            Level::INFO,
+            "Party {party_id} is {}, so there is nothing to draw",
+            party.state
+        );
+        return Ok(());
+    };
+    if signed_up.len() < party.min_participants as usize {
+        let reason = format!(
+            "only {} of the {} people it needed signed up",
+            signed_up.len(),
+            party.min_participants
+        );
+        return cancel_party(db, http, party, signed_up, reason).await;
+    }
+    if party.white_elephant {
+        return start_white_elephant(db, http, party, signed_up).await;
+    }
+    // Enough people can still be too few teams, or too few to give everyone several gifts
+    let input = db
+        .conn(move |dbc| draw::DrawInput::load(dbc, party))
+        .await?;
+    if let Some((needed, got)) = input.shortfall() {
+        let reason = format!(
+            "it needs at least {needed} {} to be drawn, but only {got} took part",
+            if input.teams.is_empty() {
+                "people"
+            } else {
+                "teams"
+            }
+        );
+        return cancel_party(db, http, input.party, signed_up, reason).await;
+    }
+    event!(Level::INFO, "Party with id {} completed", party_id);
+    draw_round(db, http, input.party).await
+}
+
+/// Cancels a party that closed with too few signups, and tells its admin why and everyone who
+/// joined that it won't happen
+async fn cancel_party(
+    db: Pool,
+    http: Arc<serenity_prelude::Http>,
+    party: PartyRecord,
+    signed_up: Vec<u64>,
+    reason: String,
+) -> Result<()> {
+    let party_id = party.id.clone();
+    if !db.conn(move |dbc| draw::cancel(dbc, &party_id)).await? {
+        return Ok(());
+    }
+    event!(
+        Level::INFO,
+        "Party {} was cancelled because {reason}",
+        party.id
+    );
+    let admin_message = format!("{} was cancelled because {reason}.", party.party_name);
+    let participant_message = format!(
+        "{} was cancelled because not enough people signed up.",
+        party.party_name
+    );
+    let recipients = std::iter::once((party.admin_id, admin_message)).chain(
+        signed_up
+            .into_iter()
+            .filter(|&uid| uid != party.admin_id)
+            .map(|uid| (uid, participant_message.clone())),
+    );
+    for (uid, message) in recipients {
+        if let Err(e) = serenity_prelude::UserId::new(uid)
+            .direct_message(&http, CreateMessage::new().content(message))
+            .await
+        {
+            event!(
+                Level::WARN,
+                "Could not tell {uid} that {} was cancelled: {e}",
+                party.id
+            );
+        }
+    }
+    Ok(())
+}
+
+/// How long the scheduler sleeps at most before looking for due jobs again
+const SCHEDULER_POLL: i64 = 60;
+
+/// Runs scheduled jobs as they fall due, retrying the ones that fail
+async fn run_scheduler(db: Pool, http: Arc<serenity_prelude::Http>) {
+    loop {
+        let now = chrono::Utc::now().timestamp();
+        match db.conn(move |dbc| scheduler::due(dbc, now)).await {
+            Ok(jobs) => {
+                for job in jobs {
+                    run_job(&db, &http, job).await;
+                }
+            }
+            Err(e) => event!(Level::ERROR, "Could not look up due jobs: {e}"),
+        }
+        let next_due = db.conn(scheduler::next_due).await.ok().flatten();
+        let wait = next_due.map_or(SCHEDULER_POLL, |next_due| {
+            (next_due - chrono::Utc::now().timestamp()).clamp(1, SCHEDULER_POLL)
+        });
+        tokio::time::sleep(Duration::from_secs(wait as u64)).await;
+    }
+}
+
+/// Runs one job and records how it went. Jobs that keep failing are reported to the party admin.
+async fn run_job(db: &Pool, http: &Arc<serenity_prelude::Http>, job: scheduler::Job) {
+    event!(
+        Level::INFO,
+        "Running the {} job for {}",
+        job.kind,
+        job.party_id
+    );
+    let (db_handle, http_handle, party_id) = (db.clone(), http.clone(), job.party_id.clone());
+    let result = match job.kind {
+        scheduler::JobKind::Draw => run_draw(db_handle, http_handle, party_id).await,
+        scheduler::JobKind::NextRound => {
+            run_next_round(db_handle, http_handle, party_id, job.due_at).await
+        }
+        scheduler::JobKind::ShippingReminder => {
+            remind_shipping(db_handle, http_handle, party_id).await
+        }
+        scheduler::JobKind::Reveal => publish_reveal(db_handle, http_handle, party_id).await,
+    };
+    let (error, unsatisfiable) = match result {
+        Ok(()) => {
+            let job_id = job.id;
+            if let Err(e) = db.conn(move |dbc| scheduler::finish(dbc, job_id)).await {
+                event!(Level::ERROR, "Could not mark job {job_id} as done: {e}");
+            }
+            return;
+        }
+        // Trying a draw that can't be made again would only fail the same way
+        Err(e) => (
+            e.to_string(),
+            e.downcast_ref::<matching::MatchError>().is_some(),
+        ),
+    };
+    event!(
+        Level::WARN,
+        "The {} job for {} failed: {error}",
+        job.kind,
+        job.party_id
+    );
+    let now = chrono::Utc::now().timestamp();
+    let (failed, failed_error) = (job.clone(), error.clone());
+    let given_up = match db
+        .conn(move |dbc| {
+            if unsatisfiable {
+                scheduler::give_up(dbc, &failed, &failed_error).map(|()| true)
+            } else {
+                scheduler::fail(dbc, &failed, &failed_error, now)
+            }
+        })
+        .await
+    {
+        Ok(given_up) => given_up,
+        Err(e) => {
+            event!(
+                Level::ERROR,
+                "Could not record the failure of job {}: {e}",
+                job.id
+            );
+            return;
+        }
+    };
+    if !given_up {
+        return;
+    }
+    event!(
+        Level::ERROR,
+        "Gave up on the {} job for {} after {} attempts",
+        job.kind,
+        job.party_id,
+        if unsatisfiable {
+            job.attempts + 1
+        } else {
+            scheduler::MAX_ATTEMPTS
+        }
+    );
+    if let Ok(Some(party)) = find_party(db, job.party_id.clone()).await {
+        let content = if unsatisfiable && job.kind == scheduler::JobKind::Draw {
+            format!(
+                "The draw for {} can't be made: {error}. Once that's sorted out, use /party redraw to draw it.",
+                party.party_name
+            )
+        } else if unsatisfiable {
+            format!(
+                "The {} for {} can't be made: {error}. Use /party schedule to see everything else that's planned.",
+                job.kind, party.party_name
+            )
+        } else {
+            format!(
+                "The {} for {} failed {} times, so it was given up on: {error}. Use /party schedule to see everything else that's planned.",
+                job.kind,
+                party.party_name,
+                scheduler::MAX_ATTEMPTS
+            )
+        };
+        let dm = serenity_prelude::UserId::new(party.admin_id)
+            .direct_message(http, CreateMessage::new().content(content))
+            .await;
+        if let Err(e) = dm {
+            event!(
+                Level::WARN,
+                "Could not report the failed job to the admin: {e}"
+            );
+        }
+    }
+}
+
+/// Reminds everyone in a drawn party that their gift has to ship soon
+async fn remind_shipping(
+    db: Pool,
+    http: Arc<serenity_prelude::Http>,
+    party_id: String,
+) -> Result<()> {
+    let party = find_party(&db, party_id.clone())
+        .await?
+        .ok_or(eyre!("Party {party_id} does not exist"))?;
+    let (PartyState::Drawn, Some(ship_by)) = (party.state, party.ship_by) else {
+        event!(
+            Level::INFO,
+            "Party {party_id} is {}, so nobody needs a shipping reminder",
+            party.state
+        );
+        return Ok(());
+    };
+    let signed_up = db.conn(move |dbc| draw::signed_up(dbc, &party_id)).await?;
+    for uid in signed_up {
+        let dm = serenity_prelude::UserId::new(uid)
+            .direct_message(
+                &http,
+                CreateMessage::new().content(format!(
+                    "Gifts for {} have to be shipped by <t:{ship_by}:F>. Use /get_my_target if you need a reminder of who you're gifting.",
+                    party.party_name
+                )),
+            )
+            .await;
+        if let Err(e) = dm {
+            event!(
+                Level::WARN,
+                "Could not remind {uid} to ship their gift: {e}"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Reveals a party's draw in the channel it was created in, once its reveal date comes
+async fn publish_reveal(
+    db: Pool,
+    http: Arc<serenity_prelude::Http>,
+    party_id: String,
+) -> Result<()> {
+    let party = find_party(&db, party_id.clone())
+        .await?
+        .ok_or(eyre!("Party {party_id} does not exist"))?;
+    if !matches!(party.state, PartyState::Drawn | PartyState::Revealed)
+        || party.current_round < party.rounds
+    {
+        event!(
+            Level::WARN,
+            "Party {party_id} is {} on round {} of {}, so it can't be revealed",
+            party.state,
+            party.current_round,
+            party.rounds
+        );
+        return Ok(());
+    }
+    let Some(channel_id) = party.channel_id else {
+        return Ok(());
+    };
+    let Some((content, batches)) = open_seal(&db, &party).await? else {
+        return Ok(());
+    };
+    for (batch, files) in batches.into_iter().enumerate() {
+        let message = CreateMessage::new().add_files(files);
+        serenity_prelude::ChannelId::new(channel_id)
+            .send_message(
+                &http,
+                if batch == 0 {
+                    message.content(content.clone())
+                } else {
+                    message
+                },
+            )
+            .await?;
+    }
+    db.conn(move |dbc| draw::finish_reveal(dbc, &party_id))
+        .await?;
+    Ok(())
+}
+
+/// Draws the daily rounds of a multi-round party that are owed by the job that fell due at `due_at`
+async fn run_next_round(
+    db: Pool,
+    http: Arc<serenity_prelude::Http>,
+    party_id: String,
+    due_at: i64,
+) -> Result<()> {
+    let party = find_party(&db, party_id.clone())
+        .await?
+        .ok_or(eyre!("Party {party_id} does not exist"))?;
+    // A job that runs again after its round was drawn mustn't draw the next day early
+    let round = (due_at - party.ends_at) / scheduler::ROUND_LENGTH + 1;
+    match party.state {
+        // The round is drawn once the first draw is, which schedules the rounds it missed
+        PartyState::Closed => {
+            event!(
+                Level::INFO,
+                "Party {party_id} hasn't been drawn yet, so round {round} waits for its first draw"
+            );
+            Ok(())
+        }
+        PartyState::Drawn
+            if party.current_round < party.rounds && i64::from(party.current_round) < round =>
+        {
+            // Every round owed by now is drawn, in case earlier ones were missed
+            let mut party = party;
+            while party.state == PartyState::Drawn
+                && party.current_round < party.rounds
+                && i64::from(party.current_round) < round
+            {
+                let drawn_round = party.current_round;
+                draw_round(db.clone(), http.clone(), party).await?;
+                party = find_party(&db, party_id.clone())
+                    .await?
+                    .ok_or(eyre!("Party {party_id} does not exist"))?;
+                // A draw that was dropped leaves nothing more to catch up on
+                if party.current_round == drawn_round {
+                    break;
                 }
             }
+            Ok(())
         }
-        Err(_e) => {
-            ctx.reply(format!("No party exists with that join phrase!"))
-                .await?;
-            return Ok(());
+        state => {
+            event!(
+                Level::INFO,
+                "Party {party_id} is {state} on round {} of {}, so there is no round to draw",
+                party.current_round,
+                party.rounds
+            );
+            Ok(())
         }
-    };
-    Ok(())
+    }
 }
 
-#[poise::command(slash_command, identifying_name = "create_party", ephemeral)]
-async fn create(
-    ctx: AppContext<'_>,
-    #[description = "How long to allow users to join this party"] signup_duration: String,
-    #[description = "The public name of this party"] party_name: String,
-) -> AppResult {
-    let rng_seed = rand::rng().random::<u32>();
-    let seedphrase = mnemonic::to_string(&rng_seed.to_be_bytes());
-    let id = uuid::Builder::from_random_bytes({
-        let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(rng_seed as u64);
-        let mut v: [u8; 16] = [0; 16];
-        rng.fill_bytes(&mut v);
-        v
-    })
-    .into_uuid();
-
-    let signup_duration = duration_str::parse(&signup_duration).map_err(|e| eyre!("{e}"))?;
-
-    ctx.data()
-        .db
+/// Draws a party's first round, or moves a drawn multi-round party on to its next one
+async fn draw_round(db: Pool, http: Arc<serenity_prelude::Http>, party: PartyRecord) -> Result<()> {
+    let party_id = party.id.clone();
+    let (mut input, drawn) = db
         .conn(move |dbc| {
-            dbc.execute(
-                &format!(
-                    "CREATE TABLE \"{id}\" (
-                        uid TEXT NOT NULL UNIQUE,
-                        name TEXT NOT NULL,
-                        hint TEXT NOT NULL
-                    );"
-                ),
-                [],
-            )
+            let drawn = draw::is_drawn(dbc, &party.id)?;
+            let mut input = draw::DrawInput::load(dbc, party)?;
+            if drawn {
+                // The round being replaced counts as the most recent history
+                input
+                    .history
+                    .insert(0, draw::current_pairs(dbc, &input.party.id)?);
+            }
+            Ok((input, drawn))
         })
         .await?;
-    let author_id_handle = ctx.author().id.get();
-    ctx.data()
-        .db
-        .conn(move |dbc| {
-            let now = chrono::Utc::now();
-            match dbc.execute(&format!(
-            "INSERT INTO party_info (id, admin_id, party_name, started_at, ends_at) VALUES (?1, ?2, ?3, ?4, ?5)"
-            ), params![
-                id.to_string(),
-                author_id_handle,
-                party_name,
-                now.timestamp(),
-                (now + signup_duration).timestamp()
-            ]) {
-                Ok(_) => {Ok(())},
-                Err(e) => {event!(Level::WARN, "SQL error: {e}"); Err(e)}
+    let round = input.party.current_round + u32::from(drawn);
+    if input.party.rounds > 1 {
+        input.party.party_name = format!(
+            "{} (day {round} of {})",
+            input.party.party_name, input.party.rounds
+        );
+    }
+    let (ids, rules) = input.rules();
+    if rules.ignores_interests(ids.len()) {
+        event!(
+            Level::WARN,
+            "Draw for {party_id} has {} people, more than the {} interests can steer, so they were ignored",
+            ids.len(),
+            matching::PREFERENCE_LIMIT
+        );
+    }
+    // The seed stays secret until the reveal, when anyone can use it to check the draw
+    let seed = rand_chacha::ChaCha20Rng::from_os_rng().next_u64();
+    let assignment = match matching::draw(
+        &ids,
+        &rules,
+        &mut rand_chacha::ChaCha20Rng::seed_from_u64(seed),
+    ) {
+        Ok(assignment) => assignment,
+        Err(e) => {
+            event!(Level::WARN, "Draw for {party_id} could not be made: {e}");
+            return Err(e.into());
+        }
+    };
+    if rules.history_len() > 0 {
+        event!(
+            Level::INFO,
+            "Draw for {party_id} avoids repeats from {} of {} earlier parties",
+            assignment.history_honored(),
+            rules.history_len()
+        );
+    }
+    let (dump, rows) = (input.dump(), input.rows(&assignment));
+    let (draw_id, ends_at, rounds) = (party_id.clone(), input.party.ends_at, input.party.rounds);
+    let now = chrono::Utc::now().timestamp();
+    let commitment = db
+        .conn_mut(move |dbc| {
+            if drawn {
+                return draw::advance_round(dbc, &draw_id, round, &dump, &rows, seed);
             }
+            let commitment = draw::record_draw(dbc, &draw_id, &dump, &rows, seed)?;
+            // Rounds that fell due while the first draw was retried are still owed
+            if commitment.is_some() {
+                scheduler::catch_up_rounds(dbc, &draw_id, ends_at, rounds, now)?;
+            }
+            Ok(commitment)
         })
         .await?;
-    event!(
-        Level::INFO,
-        "Created a party with the seed phrase {seedphrase} and the uuid {id}"
+    let Some(commitment) = commitment else {
+        event!(
+            Level::INFO,
+            "Round {round} of {party_id} was already drawn, so this draw was dropped"
+        );
+        return Ok(());
+    };
+    announce_commitment(&http, &input.party, &commitment).await;
+    Ok(())
+}
+
+/// Closes signups for a white elephant party and posts the turn order in its channel
+async fn start_white_elephant(
+    db: Pool,
+    http: Arc<serenity_prelude::Http>,
+    party: PartyRecord,
+    participants: Vec<u64>,
+) -> Result<()> {
+    let party_id = party.id.clone();
+    let game = elephant::Game::new(
+        &participants,
+        party.steal_limit,
+        &mut rand_chacha::ChaCha20Rng::from_os_rng(),
     );
-    ctx.reply(format!("Created a new party with the join phrase `{seedphrase}`. Don't forget to join your own party!")).await?;
-    let party_id = id.clone();
-    let dbhandle = ctx.data.db.clone();
-    tokio::spawn(async move {
-        tokio::time::sleep_until(tokio::time::Instant::now() + signup_duration).await;
-        let party_id_a = party_id.clone();
-        let mut signed_up = dbhandle
-            .conn(move |dbc| {
-                #[derive(Clone)]
-                struct UserResponse {
-                    pub uid: u64,
-                    pub name: String,
-                    pub hint: String,
-                }
-                let mut query = dbc.prepare(&format!("SELECT * FROM \"{party_id_a}\";"))?;
-
-                let responses = query.query_map([], |row| {
-                    Ok(UserResponse {
-                        uid: row.get::<_, u64>("uid")?,
-                        name: row.get::<_, String>("name")?,
-                        hint: row.get::<_, String>("hint")?,
-                    })
-                })?;
-                let mut ovec = Vec::default();
-                for user_response in responses {
-                    ovec.push(user_response?);
-                }
-                ovec.shuffle(&mut rand::rng());
-                Ok(ovec)
-            })
-            .await?;
-        event!(Level::INFO, "Party with id {} completed", party_id);
-        let givers = {
-            let mut s = signed_up
-                .iter()
-                .map(|user| user.uid.clone())
-                .collect::<Vec<_>>();
-            let mut no_dupes = true;
-            loop {
-                s.shuffle(&mut rand::rng());
-                for i in 0..s.len() {
-                    if s[i] == signed_up[i].uid {
-                        event!(Level::INFO, "Shuffle collision detected, rerandomizing");
-                        no_dupes = false;
-                        break;
-                    }
-                }
-                if no_dupes {
-                    break;
-                }
+    let saved = game.clone();
+    let started = db
+        .conn_mut(move |dbc| {
+            let tx = dbc.transaction()?;
+            if lifecycle::current(&tx, &party_id)? != PartyState::Closed {
+                return Ok(false);
             }
-            s.reverse();
-            s
-        };
-        let party_id_b = party_id.clone();
-        dbhandle
-            .conn(move |dbc| {
-                dbc.execute(
-                    &format!(
-                        "CREATE TABLE \"{party_id_b}-matches\" (
-                            giver_id integer not null unique,
-                            receiver_id integer not null unique,
-                            receiver_name text not null,
-                            receiver_hint text not null
-                        );"
-                    ),
-                    [],
-                )?;
-                for giver_id in givers {
-                    let receiver = signed_up.pop().unwrap();
-                    dbc.execute(
-                        &format!(
-                            "INSERT INTO \"{party_id_b}-matches\"
-                            giver_id, receiver_id, receiver_name, receiver_hint
-                        VALUES
-                           (?1,       ?2,          ?3,            ?4)"
-                        ),
-                        params![giver_id, receiver.uid, receiver.name, receiver.hint],
-                    )?;
-                }
-                dbc.execute(
-                    "UPDATE TABLE party_info SET matches_made = true WHERE party_id = ?1",
-                    [party_id_b.to_string()],
-                )?;
-                Ok(())
-            })
-            .await?;
-        AppResult::Ok(())
-    });
+            elephant::save(&tx, &party_id, &saved)?;
+            lifecycle::transition(&tx, &party_id, PartyState::Drawn)?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await?;
+    if !started {
+        event!(
+            Level::INFO,
+            "White elephant game for {} had already started",
+            party.id
+        );
+        return Ok(());
+    }
+    event!(Level::INFO, "White elephant game for {} started", party.id);
+    let Some(channel_id) = party.channel_id else {
+        return Ok(());
+    };
+    let order = game
+        .order()
+        .iter()
+        .enumerate()
+        .map(|(turn, uid)| format!("{}. <@{uid}>\n", turn + 1))
+        .collect::<String>();
+    serenity_prelude::ChannelId::new(channel_id)
+        .say(
+            &http,
+            format!(
+                "The white elephant game for **{}** is starting! Each gift can be stolen {} times. The turn order is:\n{order}{}",
+                party.party_name,
+                party.steal_limit,
+                game.summary()
+            ),
+        )
+        .await?;
     Ok(())
 }
+
+/// Posts a draw's commitment to the channel the party was created in
+async fn announce_commitment(http: &serenity_prelude::Http, party: &PartyRecord, commitment: &str) {
+    let Some(channel_id) = party.channel_id else {
+        return;
+    };
+    let announcement = serenity_prelude::ChannelId::new(channel_id)
+        .say(
+            http,
+            format!(
+                "The draw for **{}** is in! Its commitment is `{commitment}`. The seed will be published at the reveal so anyone can check nothing was changed.",
+                party.party_name
+            ),
+        )
+        .await;
+    if let Err(e) = announcement {
+        event!(
+            Level::WARN,
+            "Could not announce the draw for {}: {e}",
+            party.id
+        );
+    }
+}
+
 #[poise::command(slash_command, ephemeral)]
-async fn get_my_target(ctx: AppContext<'_>) -> AppResult {
-    let uid_handle = ctx.author().id.get().clone();
+async fn get_my_target(
+    ctx: AppContext<'_>,
+    #[description = "Which day of an advent party to show (defaults to the latest)"]
+    #[min = 1]
+    #[max = 25]
+    round: Option<u32>,
+) -> AppResult {
+    let uid_handle = ctx.author().id.get();
     let user_parties = ctx
         .data
         .db
         .conn(move |dbc| {
-            let mut query = dbc.prepare("SELECT id FROM party_info")?;
-            let parties = {
-                let mut ov = Vec::new();
-                let rows = query.query_map([], |info| Ok(info.get::<_, String>("id")?))?;
-                for pid in rows {
-                    ov.push(pid?);
-                }
-                ov
-            };
-            let mut joined_parties = vec![];
-            for party in parties {
-                if dbc
-                    .query_one(
-                        &format!("SELECT uid FROM \"{party}\" where uid = ?1;"),
-                        [uid_handle],
-                        |row| {
-                            row.get::<_, String>("uid")?;
-                            Ok(())
-                        },
-                    )
-                    .is_ok()
-                {
-                    joined_parties.push(party);
-                }
-            }
-            Ok(joined_parties)
+            dbc.prepare("SELECT party_id FROM participants WHERE uid = ?1")?
+                .query_map([uid_handle], |row| row.get::<_, String>("party_id"))?
+                .collect::<async_sqlite::rusqlite::Result<Vec<_>>>()
         })
         .await?;
     let mut responses = HashMap::<String, CreateReply>::default();
@@ -463,20 +2072,48 @@ async fn get_my_target(ctx: AppContext<'_>) -> AppResult {
                 struct PartyData {
                     party_name: String,
                     ends_at: i64,
+                    white_elephant: bool,
+                    rounds: u32,
+                    current_round: u32,
+                    state: PartyState,
+                    ship_by: Option<i64>,
                 }
-                Ok(dbc.query_one(
-                    "SELECT party_name, ends_at FROM party_info WHERE id = ?1",
+                dbc.query_one(
+                    "SELECT party_name, ends_at, white_elephant, rounds, current_round, state, ship_by FROM parties WHERE id = ?1",
                     [party_id_handle],
                     |row| {
                         Ok(PartyData {
                             party_name: row.get::<_, String>("party_name")?,
                             ends_at: row.get::<_, i64>("ends_at")?,
+                            white_elephant: row.get::<_, bool>("white_elephant")?,
+                            rounds: row.get::<_, u32>("rounds")?,
+                            current_round: row.get::<_, u32>("current_round")?,
+                            state: row.get::<_, PartyState>("state")?,
+                            ship_by: row.get::<_, Option<i64>>("ship_by")?,
                         })
                     },
-                )?)
+                )
             })
             .await?;
-        if party_data.ends_at > chrono::Utc::now().timestamp() {
+        if matches!(party_data.state, PartyState::Closed | PartyState::Cancelled) {
+            let description = if party_data.state == PartyState::Cancelled {
+                "This party was cancelled because too few people signed up."
+            } else {
+                "Signups have closed, but this party hasn't been drawn yet."
+            };
+            responses.insert(
+                party_data.party_name.clone(),
+                CreateReply {
+                    embeds: vec![
+                        CreateEmbed::new()
+                            .title(party_data.party_name)
+                            .description(description),
+                    ],
+                    components: Some(vec![]),
+                    ..Default::default()
+                },
+            );
+        } else if party_data.state == PartyState::Open {
             responses.insert(
                 party_data.party_name.clone(),
                 CreateReply {
@@ -490,39 +2127,145 @@ async fn get_my_target(ctx: AppContext<'_>) -> AppResult {
                     ..Default::default()
                 },
             );
+        } else if party_data.white_elephant {
+            responses.insert(
+                party_data.party_name.clone(),
+                CreateReply {
+                    embeds: vec![CreateEmbed::new().title(party_data.party_name).description(
+                        "This party plays white elephant, so nobody has a target. Use `/elephant status` to follow the game.",
+                    )],
+                    components: Some(vec![]),
+                    ..Default::default()
+                },
+            );
+        } else if party_data.rounds > 1
+            && round.is_some_and(|round| round > party_data.current_round)
+        {
+            responses.insert(
+                party_data.party_name.clone(),
+                CreateReply {
+                    embeds: vec![CreateEmbed::new().title(party_data.party_name).description(
+                        format!(
+                            "Day {} hasn't been drawn yet, today is day {}.",
+                            round.unwrap_or_default(),
+                            party_data.current_round
+                        ),
+                    )],
+                    components: Some(vec![]),
+                    ..Default::default()
+                },
+            );
         } else {
             let uid_handle_b = ctx.author().id.get();
             let party_id_handle_b = party_id.clone();
-            let user_match = ctx
+            // Earlier days of an advent party are archived, the latest one is in assignments
+            let shown_round = round
+                .filter(|_| party_data.rounds > 1)
+                .unwrap_or(party_data.current_round);
+            let archived = shown_round < party_data.current_round;
+            let (user_matches, budget) = ctx
                 .data
                 .db
                 .conn(move |dbc| {
                     struct MatchData {
                         name: String,
                         hint: String,
+                        team: Option<String>,
                     }
-                    Ok(dbc.query_one(
-                        &format!(
-                            "SELECT * FROM \"{party_id_handle_b}-matches\" WHERE giver_id = ?1"
-                        ),
-                        [uid_handle_b],
-                        |row| {
-                            Ok(MatchData {
-                                name: row.get::<_, String>("name")?,
-                                hint: row.get::<_, String>("hint")?,
-                            })
-                        },
-                    )?)
+                    fn read_match(
+                        row: &async_sqlite::rusqlite::Row<'_>,
+                    ) -> async_sqlite::rusqlite::Result<MatchData> {
+                        Ok(MatchData {
+                            name: row.get::<_, String>("receiver_name")?,
+                            hint: row.get::<_, String>("receiver_hint")?,
+                            team: row.get::<_, Option<String>>("receiver_team")?,
+                        })
+                    }
+                    let ovec = if archived {
+                        dbc.prepare(
+                            "SELECT receiver_name, receiver_hint, receiver_team FROM party_rounds WHERE party_id = ?1 AND round = ?2 AND giver_id = ?3 ORDER BY rowid",
+                        )?
+                        .query_map(params![party_id_handle_b, shown_round, uid_handle_b], read_match)?
+                        .collect::<async_sqlite::rusqlite::Result<Vec<_>>>()?
+                    } else {
+                        dbc.prepare(
+                            "SELECT receiver_name, receiver_hint, receiver_team FROM assignments WHERE party_id = ?1 AND giver_id = ?2 ORDER BY rowid",
+                        )?
+                        .query_map(params![party_id_handle_b, uid_handle_b], read_match)?
+                        .collect::<async_sqlite::rusqlite::Result<Vec<_>>>()?
+                    };
+                    // Givers and receivers share a budget tier, so the giver's own is the agreed one
+                    let budget = dbc.query_one(
+                        "SELECT budget FROM participants WHERE party_id = ?1 AND uid = ?2",
+                        params![party_id_handle_b, uid_handle_b],
+                        |row| row.get::<_, Option<u32>>("budget"),
+                    )?;
+                    Ok((ovec, budget))
                 })
                 .await?;
+            let decode =
+                |field: String| String::from_utf8(BASE64_STANDARD.decode(field).unwrap()).unwrap();
+            let description = match user_matches.as_slice() {
+                // Team parties list every member of each receiving team under its name
+                user_matches if user_matches.iter().any(|m| m.team.is_some()) => {
+                    let mut description = String::new();
+                    let mut current_team = None;
+                    for user_match in user_matches {
+                        if current_team != user_match.team.as_ref() {
+                            current_team = user_match.team.as_ref();
+                            description.push_str(&format!(
+                                "Your team is gifting **{}**.\n",
+                                current_team.map_or("", |team| team.as_str())
+                            ));
+                        }
+                        description.push_str(&format!(
+                            "**{}** wanted you to know this:```\n{}\n```",
+                            decode(user_match.name.clone()),
+                            decode(user_match.hint.clone())
+                        ));
+                    }
+                    description
+                }
+                [user_match] => format!(
+                    "You have been matched with {}.\n They wanted you to know this:```\n{}\n```",
+                    decode(user_match.name.clone()),
+                    decode(user_match.hint.clone())
+                ),
+                user_matches => {
+                    let mut description = format!(
+                        "You have been matched with {} people.\n",
+                        user_matches.len()
+                    );
+                    for user_match in user_matches {
+                        description.push_str(&format!(
+                            "**{}** wanted you to know this:```\n{}\n```",
+                            decode(user_match.name.clone()),
+                            decode(user_match.hint.clone())
+                        ));
+                    }
+                    description
+                }
+            };
+            let mut embed = CreateEmbed::new()
+                .title(party_data.party_name.clone())
+                .description(description);
+            if let Some(budget) = budget {
+                embed = embed.field("Budget", format!("${budget}"), true);
+            }
+            if let Some(ship_by) = party_data.ship_by {
+                embed = embed.field("Ship by", format!("<t:{ship_by}:D>"), true);
+            }
+            if party_data.rounds > 1 {
+                embed = embed.field(
+                    "Day",
+                    format!("{shown_round} of {}", party_data.rounds),
+                    true,
+                );
+            }
             responses.insert(
-                party_data.party_name.clone(),
+                party_data.party_name,
                 CreateReply {
-                    embeds: vec![CreateEmbed::new().title(party_data.party_name).description(format!(
-                        "You have been matched with {}.\n They wanted you to know this:```\n{}\n```",
-                        String::from_utf8(BASE64_STANDARD.decode(user_match.name).unwrap()).unwrap(),
-                        String::from_utf8(BASE64_STANDARD.decode(user_match.hint).unwrap()).unwrap()
-                    ))],
+                    embeds: vec![embed],
                     components: Some(vec![]),
                     ..Default::default()
                 },
@@ -558,7 +2301,7 @@ async fn get_my_target(ctx: AppContext<'_>) -> AppResult {
     match catch_interaction {
         Some(interaction) => match interaction.data.kind {
             ComponentInteractionDataKind::StringSelect { values } => {
-                let party_name = values.get(0).unwrap();
+                let party_name = values.first().unwrap();
                 reply_handle
                     .edit(
                         Context::Application(ctx),
@@ -584,6 +2327,147 @@ async fn get_my_target(ctx: AppContext<'_>) -> AppResult {
     Ok(())
 }
 
+#[poise::command(slash_command, subcommands("open", "steal", "status"))]
+async fn elephant(_ctx: AppContext<'_>) -> AppResult {
+    event!(
+        Level::WARN,
+        "Impossible parent command 'elephant' was called!"
+    );
+    Ok(())
+}
+
+/// Unwraps a random gift on your white elephant turn
+#[poise::command(slash_command, identifying_name = "elephant_open")]
+async fn open(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+    #[description = "What was inside"] gift: String,
+) -> AppResult {
+    let player = ctx.author().id.get();
+    play_elephant(ctx, joinphrase, move |game| {
+        let opened = game.open(player, gift, &mut rand_chacha::ChaCha20Rng::from_os_rng())?;
+        Ok(format!(
+            "<@{player}> unwrapped **{}**, brought by <@{}>!",
+            opened.description.as_deref().unwrap_or("a gift"),
+            opened.brought_by
+        ))
+    })
+    .await
+}
+
+/// Takes someone's gift on your white elephant turn
+#[poise::command(slash_command, identifying_name = "elephant_steal")]
+async fn steal(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+    #[description = "Who to steal from"] from: serenity_prelude::User,
+) -> AppResult {
+    let player = ctx.author().id.get();
+    let victim = from.id.get();
+    play_elephant(ctx, joinphrase, move |game| {
+        let stolen = game.steal(player, victim)?;
+        Ok(format!(
+            "<@{player}> stole **{}** from <@{victim}>!",
+            stolen.description.as_deref().unwrap_or("a gift")
+        ))
+    })
+    .await
+}
+
+/// Shows who holds what in a white elephant game, and whose turn it is
+#[poise::command(slash_command, identifying_name = "elephant_status", ephemeral)]
+async fn status(
+    ctx: AppContext<'_>,
+    #[description = "The join phrase for the party"] joinphrase: String,
+) -> AppResult {
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.reply("Incorrect join phrase").await?;
+        return Ok(());
+    };
+    let Some(party) = find_party(&ctx.data.db, party_id.to_string()).await? else {
+        ctx.reply("No party exists with that join phrase!").await?;
+        return Ok(());
+    };
+    let game = ctx
+        .data
+        .db
+        .conn(move |dbc| elephant::load(dbc, &party_id.to_string()))
+        .await?;
+    match game {
+        Some(game) => ctx.reply(game.summary()).await?,
+        None => {
+            ctx.reply(format!(
+                "{} isn't playing white elephant right now",
+                party.party_name
+            ))
+            .await?
+        }
+    };
+    Ok(())
+}
+
+/// Makes a move in a party's white elephant game, saves it and posts the result
+async fn play_elephant(
+    ctx: AppContext<'_>,
+    joinphrase: String,
+    play: impl FnOnce(&mut elephant::Game) -> Result<String, elephant::MoveError> + Send + 'static,
+) -> AppResult {
+    let Some(party_id) = party_id_from_phrase(joinphrase) else {
+        ctx.send(
+            CreateReply::default()
+                .content("Incorrect join phrase")
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(());
+    };
+    let Some(party) = find_party(&ctx.data.db, party_id.to_string()).await? else {
+        ctx.send(
+            CreateReply::default()
+                .content("No party exists with that join phrase!")
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(());
+    };
+    let outcome = ctx
+        .data
+        .db
+        .conn_mut(move |dbc| {
+            let tx = dbc.transaction()?;
+            let Some(mut game) = elephant::load(&tx, &party_id.to_string())? else {
+                return Ok(None);
+            };
+            let played = play(&mut game);
+            if played.is_ok() {
+                elephant::save(&tx, &party_id.to_string(), &game)?;
+            }
+            if game.up().is_none() {
+                lifecycle::transition(&tx, &party_id.to_string(), PartyState::Archived)?;
+            }
+            tx.commit()?;
+            Ok(Some((game, played)))
+        })
+        .await?;
+    let content = match outcome {
+        None => format!(
+            "{} isn't playing white elephant right now",
+            party.party_name
+        ),
+        Some((_, Err(e))) => format!("You can't do that: {e}"),
+        Some((game, Ok(played))) => {
+            if game.up().is_none() {
+                event!(Level::INFO, "White elephant game for {} finished", party.id);
+            }
+            ctx.reply(format!("{played}\n{}", game.summary())).await?;
+            return Ok(());
+        }
+    };
+    ctx.send(CreateReply::default().content(content).ephemeral(true))
+        .await?;
+    Ok(())
+}
+
 #[poise::command(slash_command, ephemeral)]
 async fn ping(ctx: AppContext<'_>) -> AppResult {
     ctx.reply("Pong!").await?;
@@ -596,6 +2480,15 @@ async fn main() -> Result<()> {
         .with_max_level(Level::INFO)
         .finish()
         .init();
+    let args = std::env::args().collect::<Vec<_>>();
+    if let Some("replay") = args.get(1).map(String::as_str) {
+        let (Some(path), Some(seed)) = (args.get(2), args.get(3)) else {
+            return Err(eyre!(
+                "Usage: secretsatan replay <draw file> <seed> [commitment]"
+            ));
+        };
+        return replay(path, seed, args.get(4).map(String::as_str));
+    }
     event!(Level::INFO, "Reading .env");
     dotenv::dotenv()?;
     let token = std::env::var("DISCORD_TOKEN")?;
@@ -606,150 +2499,12 @@ async fn main() -> Result<()> {
         .journal_mode(async_sqlite::JournalMode::Wal)
         .open()
         .await?;
-    db_connection
-        .conn(|dbc| {
-            dbc.execute(
-                "CREATE TABLE IF NOT EXISTS party_info  (
-                    id text not null,
-                    admin_id integer not null,
-                    party_name text not null,
-                    started_at integer not null,
-                    ends_at integer not null,
-                    matches_made bool not null default true
-                );",
-                [],
-            )
-        })
-        .await?;
-    let mut timer_pool = tokio::task::JoinSet::<Result<()>>::new();
-    struct PartyInfo {
-        pub ends_at: i64,
-        pub id: String,
-    }
-    let pending_parties = db_connection
-        .conn(|dbc| {
-            let mut statement = dbc.prepare("SELECT id, ends_at FROM party_info;")?;
-            let rows = statement.query_map([], |row| {
-                Ok(PartyInfo {
-                    id: row.get::<&str, String>("id")?.clone(),
-                    ends_at: row.get::<&str, i64>("ends_at")?.clone(),
-                })
-            })?;
-            let mut ovec = Vec::<PartyInfo>::default();
-            for line in rows {
-                let l = line?;
-                if l.ends_at < chrono::Utc::now().timestamp() {
-                    event!(Level::INFO, "{} already completed", l.id);
-                    continue;
-                }
-                ovec.push(l);
-            }
-            Ok(ovec)
-        })
-        .await?;
-    for party in pending_parties {
-        event!(Level::INFO, "Spawning timer for {}", party.id);
-        let db_handle = db_connection.clone();
-        timer_pool.spawn(async move {
-            tokio::time::sleep_until(
-                tokio::time::Instant::now()
-                    + chrono::DateTime::from_timestamp_secs(party.ends_at)
-                        .ok_or(eyre!("Failed to parse party end time"))?
-                        .signed_duration_since(chrono::Utc::now())
-                        .to_std()?,
-            )
-            .await;
-
-            let party_id_a = party.id.clone();
-            let mut signed_up = db_handle
-                .conn(move |dbc| {
-                    #[derive(Clone)]
-                    struct UserResponse {
-                        pub uid: u64,
-                        pub name: String,
-                        pub hint: String,
-                    }
-                    let mut query = dbc.prepare(&format!("SELECT * FROM \"{party_id_a}\";"))?;
-
-                    let responses = query.query_map([], |row| {
-                        Ok(UserResponse {
-                            uid: row.get::<_, u64>("uid")?,
-                            name: row.get::<_, String>("name")?,
-                            hint: row.get::<_, String>("hint")?,
-                        })
-                    })?;
-                    let mut ovec = Vec::default();
-                    for user_response in responses {
-                        ovec.push(user_response?);
-                    }
-                    ovec.shuffle(&mut rand::rng());
-                    Ok(ovec)
-                })
-                .await?;
-            event!(Level::INFO, "Party with id {} completed", party.id);
-            let givers = {
-                let mut s = signed_up
-                    .iter()
-                    .map(|user| user.uid.clone())
-                    .collect::<Vec<_>>();
-                let mut no_dupes = true;
-                loop {
-                    s.shuffle(&mut rand::rng());
-                    for i in 0..s.len() {
-                        if s[i] == signed_up[i].uid {
-                            event!(Level::INFO, "Shuffle collision detected, rerandomizing");
-                            no_dupes = false;
-                            break;
-                        }
-                    }
-                    if no_dupes {
-                        break;
-                    }
-                }
-                s.reverse();
-                s
-            };
-            let party_id_b = party.id.clone();
-            db_handle
-                .conn(move |dbc| {
-                    dbc.execute(
-                        &format!(
-                            "CREATE TABLE \"{party_id_b}-matches\" (
-                                giver_id integer not null unique,
-                                receiver_id integer not null unique,
-                                receiver_name text not null,
-                                receiver_hint text not null
-                            );"
-                        ),
-                        [],
-                    )?;
-                    for giver_id in givers {
-                        let receiver = signed_up.pop().unwrap();
-                        dbc.execute(
-                            &format!(
-                                "INSERT INTO \"{party_id_b}-matches\"
-                                giver_id, receiver_id, receiver_name, receiver_hint
-                            VALUES
-                               (?1,       ?2,          ?3,            ?4)"
-                            ),
-                            params![giver_id, receiver.uid, receiver.name, receiver.hint],
-                        )?;
-                    }
-                    dbc.execute(
-                        "UPDATE TABLE party_info SET matches_made = true WHERE party_id = ?1",
-                        [party_id_b],
-                    )?;
-                    Ok(())
-                })
-                .await?;
-            Ok(())
-        });
-    }
-
+    db_connection.conn(schema::migrate).await?;
     event!(Level::INFO, "Setting up bot");
+    let state_db = db_connection.clone();
     let app_framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![party(), ping(), info(), get_my_target()],
+            commands: vec![party(), elephant(), ping(), info(), get_my_target()],
             ..Default::default()
         })
         .setup(|ctx, _ready, fw| {
@@ -770,9 +2525,7 @@ async fn main() -> Result<()> {
                     .await?;
                     event!(Level::INFO, "Commands registered");
                 }
-                Ok(AppState {
-                    db: db_connection.clone(),
-                })
+                Ok(AppState { db: state_db })
             })
         })
         .build();
@@ -780,6 +2533,41 @@ async fn main() -> Result<()> {
     let mut app_client = poise::serenity_prelude::ClientBuilder::new(token, _APP_INTENTS)
         .framework(app_framework)
         .await?;
+    // Parties that closed while the bot was offline are drawn late, or would never be drawn at all
+    let now = chrono::Utc::now().timestamp();
+    let overdue = db_connection
+        .conn(move |dbc| scheduler::catch_up(dbc, now))
+        .await?;
+    for party_id in overdue {
+        event!(
+            Level::WARN,
+            "{party_id} closed while the bot was offline, drawing it now"
+        );
+        let Some(party) = find_party(&db_connection, party_id).await? else {
+            continue;
+        };
+        let dm = serenity_prelude::UserId::new(party.admin_id)
+            .direct_message(
+                &app_client.http,
+                CreateMessage::new().content(format!(
+                    "Signups for {} closed while the bot was offline, so it is being drawn now.",
+                    party.party_name
+                )),
+            )
+            .await;
+        if let Err(e) = dm {
+            event!(
+                Level::WARN,
+                "Could not tell the admin of {} about the late draw: {e}",
+                party.id
+            );
+        }
+    }
+    tokio::spawn(run_scheduler(
+        db_connection.clone(),
+        app_client.http.clone(),
+    ));
+
     event!(Level::INFO, "Starting...");
     app_client.start().await?;
     Ok(())
@@ -0,0 +1,1662 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use rand::{Rng, seq::SliceRandom};
+
+/// A single giver -> receiver edge of a draw
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Pair {
+    pub giver: u64,
+    pub receiver: u64,
+}
+
+/// A complete, validated set of pairings for one party
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pairs: Vec<Pair>,
+    history_honored: usize,
+}
+
+/// Extra constraints a party places on its draw
+#[derive(Debug, Clone)]
+pub struct Rules {
+    exclusions: HashSet<(u64, u64)>,
+    history: Vec<HashSet<(u64, u64)>>,
+    single_cycle: bool,
+    gifts_per_person: usize,
+    pins: Vec<(u64, u64)>,
+    regions: HashMap<u64, String>,
+    ships_abroad: HashSet<u64>,
+    tiers: HashMap<u64, u32>,
+    interests: HashMap<u64, HashSet<String>>,
+}
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            exclusions: HashSet::default(),
+            history: Vec::default(),
+            single_cycle: false,
+            gifts_per_person: 1,
+            pins: Vec::default(),
+            regions: HashMap::default(),
+            ships_abroad: HashSet::default(),
+            tiers: HashMap::default(),
+            interests: HashMap::default(),
+        }
+    }
+}
+impl Rules {
+    /// Makes everyone give to (and receive from) `gifts` different people
+    pub fn set_gifts_per_person(&mut self, gifts: usize) {
+        self.gifts_per_person = gifts.max(1);
+    }
+    /// Requires the whole party to form one gift loop instead of several smaller ones
+    pub fn require_single_cycle(&mut self) {
+        self.single_cycle = true;
+    }
+    /// Forbids `a` and `b` from drawing each other in either direction
+    pub fn exclude(&mut self, a: u64, b: u64) {
+        self.exclusions.insert((a.min(b), a.max(b)));
+    }
+    /// Adds one earlier draw whose giver -> receiver pairs should not repeat.
+    ///
+    /// Call this from the most recent draw backwards; older draws are given up first
+    /// when avoiding all of them is impossible.
+    pub fn avoid_repeats(&mut self, pairs: impl IntoIterator<Item = (u64, u64)>) {
+        self.history.push(pairs.into_iter().collect());
+    }
+    /// Forces `giver` to give to `receiver`, leaving only everyone else to chance
+    pub fn pin(&mut self, giver: u64, receiver: u64) {
+        if !self.pins.contains(&(giver, receiver)) {
+            self.pins.push((giver, receiver));
+        }
+    }
+    /// Places `uid` in a shipping region. Unless they ship abroad, they only give to people
+    /// in the same region; people without a region are not limited at all.
+    pub fn set_region(&mut self, uid: u64, region: &str, ships_abroad: bool) {
+        self.regions.insert(uid, region.trim().to_lowercase());
+        if ships_abroad {
+            self.ships_abroad.insert(uid);
+        }
+    }
+    /// Puts `uid` in a budget tier, so they only give to and receive from people in the same one
+    pub fn set_tier(&mut self, uid: u64, tier: u32) {
+        self.tiers.insert(uid, tier);
+    }
+    /// Tags `uid` with interests. Givers who share interests with a receiver are more likely
+    /// to draw them, though never certain to, so the draw stays unpredictable.
+    pub fn set_interests(&mut self, uid: u64, tags: impl IntoIterator<Item = impl AsRef<str>>) {
+        let tags = tags
+            .into_iter()
+            .map(|tag| tag.as_ref().trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect::<HashSet<_>>();
+        if !tags.is_empty() {
+            self.interests.insert(uid, tags);
+        }
+    }
+    /// Whether `participants` people are too many for interests to steer the draw
+    pub fn ignores_interests(&self, participants: usize) -> bool {
+        !self.interests.is_empty() && participants > PREFERENCE_LIMIT
+    }
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+    pub fn allows(&self, giver: u64, receiver: u64) -> bool {
+        if let (Some(from), Some(to)) = (self.regions.get(&giver), self.regions.get(&receiver))
+            && from != to
+            && !self.ships_abroad.contains(&giver)
+        {
+            return false;
+        }
+        if let (Some(from), Some(to)) = (self.tiers.get(&giver), self.tiers.get(&receiver))
+            && from != to
+        {
+            return false;
+        }
+        giver != receiver
+            && !self
+                .exclusions
+                .contains(&(giver.min(receiver), giver.max(receiver)))
+    }
+    /// Like [`Rules::allows`], but also rejects pairs from the `depth` most recent earlier draws
+    fn allows_with_history(&self, giver: u64, receiver: u64, depth: usize) -> bool {
+        if self.pins.contains(&(giver, receiver)) {
+            return true;
+        }
+        // With one gift each, a pinned giver or receiver has no room for anyone else
+        if self.gifts_per_person == 1
+            && self.pins.iter().any(|&(pinned_giver, pinned_receiver)| {
+                pinned_giver == giver || pinned_receiver == receiver
+            })
+        {
+            return false;
+        }
+        self.allows(giver, receiver)
+            && !self.history[..depth]
+                .iter()
+                .any(|draw| draw.contains(&(giver, receiver)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchError {
+    NotEnoughParticipants { needed: usize, got: usize },
+    DuplicateParticipant(u64),
+    SelfGift(u64),
+    UnknownParticipant(u64),
+    NotGiving(u64),
+    NotReceiving(u64),
+    Forbidden(u64, u64),
+    DuplicatePair(u64, u64),
+    SeveralLoops(usize),
+    SingleCycleWithSeveralGifts,
+    TooManyPins(u64),
+    MissingPin(u64, u64),
+    CannotSplice(u64),
+    CannotInsert(u64),
+    RegionsTooSmall(Vec<String>),
+    TiersTooSmall(Vec<u32>),
+    Unsatisfiable,
+    SearchLimit,
+}
+impl std::fmt::Display for MatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MatchError::NotEnoughParticipants { needed, got } => {
+                write!(
+                    f,
+                    "this draw needs at least {needed} participants, got {got}"
+                )
+            }
+            MatchError::DuplicateParticipant(uid) => write!(f, "{uid} is listed more than once"),
+            MatchError::SelfGift(uid) => write!(f, "{uid} was assigned to themselves"),
+            MatchError::UnknownParticipant(uid) => write!(f, "{uid} is not a participant"),
+            MatchError::NotGiving(uid) => {
+                write!(f, "{uid} does not give the right number of gifts")
+            }
+            MatchError::NotReceiving(uid) => {
+                write!(f, "{uid} does not receive the right number of gifts")
+            }
+            MatchError::Forbidden(giver, receiver) => {
+                write!(f, "{giver} is not allowed to give to {receiver}")
+            }
+            MatchError::DuplicatePair(giver, receiver) => {
+                write!(f, "{giver} gives to {receiver} more than once")
+            }
+            MatchError::SeveralLoops(n) => write!(f, "the draw forms {n} loops instead of one"),
+            MatchError::SingleCycleWithSeveralGifts => {
+                write!(f, "a single gift loop only works with one gift per person")
+            }
+            MatchError::TooManyPins(uid) => write!(f, "{uid} is pinned to too many people"),
+            MatchError::MissingPin(giver, receiver) => {
+                write!(f, "the pinned pair {giver} -> {receiver} is missing")
+            }
+            MatchError::CannotSplice(uid) => {
+                write!(f, "{uid} can't be taken out without redrawing everyone")
+            }
+            MatchError::CannotInsert(uid) => {
+                write!(f, "{uid} can't be added without redrawing everyone")
+            }
+            MatchError::RegionsTooSmall(regions) => write!(
+                f,
+                "too few people can gift each other in {}",
+                regions.join(", ")
+            ),
+            MatchError::TiersTooSmall(tiers) => write!(
+                f,
+                "too few people share the budget tiers {}",
+                tiers
+                    .iter()
+                    .map(|tier| tier.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            MatchError::Unsatisfiable => {
+                write!(f, "no assignment can satisfy the party's rules")
+            }
+            MatchError::SearchLimit => {
+                write!(f, "no single gift loop was found within the search limit")
+            }
+        }
+    }
+}
+impl std::error::Error for MatchError {}
+
+impl Assignment {
+    pub fn pairs(&self) -> &[Pair] {
+        &self.pairs
+    }
+    /// How many of the earlier draws passed to [`Rules::avoid_repeats`] this assignment avoids
+    pub fn history_honored(&self) -> usize {
+        self.history_honored
+    }
+    /// Splits the assignment into its gift loops, each listed as givers in giving order.
+    ///
+    /// Loops only exist when everyone gives a single gift; otherwise this is empty.
+    pub fn cycles(&self) -> Vec<Vec<u64>> {
+        let receiver_of = self
+            .pairs
+            .iter()
+            .map(|pair| (pair.giver, pair.receiver))
+            .collect::<HashMap<_, _>>();
+        if receiver_of.len() != self.pairs.len() {
+            return Vec::new();
+        }
+        let mut seen = HashSet::new();
+        let mut cycles = Vec::new();
+        for pair in &self.pairs {
+            let mut cycle = Vec::new();
+            let mut giver = pair.giver;
+            while seen.insert(giver) {
+                cycle.push(giver);
+                giver = receiver_of[&giver];
+            }
+            if !cycle.is_empty() {
+                cycles.push(cycle);
+            }
+        }
+        cycles
+    }
+    /// Checks that every participant gives and receives the right number of gifts,
+    /// nobody gives to the same person twice and every pair is allowed
+    pub fn validate(&self, participants: &[u64], rules: &Rules) -> Result<(), MatchError> {
+        let everyone = participant_set(participants)?;
+        let mut given = HashMap::<u64, usize>::new();
+        let mut received = HashMap::<u64, usize>::new();
+        let mut seen = HashSet::new();
+        for pair in &self.pairs {
+            if pair.giver == pair.receiver {
+                return Err(MatchError::SelfGift(pair.giver));
+            }
+            if !rules.allows(pair.giver, pair.receiver) {
+                return Err(MatchError::Forbidden(pair.giver, pair.receiver));
+            }
+            for uid in [pair.giver, pair.receiver] {
+                if !everyone.contains(&uid) {
+                    return Err(MatchError::UnknownParticipant(uid));
+                }
+            }
+            if !seen.insert(*pair) {
+                return Err(MatchError::DuplicatePair(pair.giver, pair.receiver));
+            }
+            *given.entry(pair.giver).or_default() += 1;
+            *received.entry(pair.receiver).or_default() += 1;
+        }
+        for uid in participants {
+            if given.get(uid).copied().unwrap_or_default() != rules.gifts_per_person {
+                return Err(MatchError::NotGiving(*uid));
+            }
+            if received.get(uid).copied().unwrap_or_default() != rules.gifts_per_person {
+                return Err(MatchError::NotReceiving(*uid));
+            }
+        }
+        for &(giver, receiver) in &rules.pins {
+            if !seen.contains(&Pair { giver, receiver }) {
+                return Err(MatchError::MissingPin(giver, receiver));
+            }
+        }
+        if rules.single_cycle {
+            let loops = self.cycles().len();
+            if loops != 1 {
+                return Err(MatchError::SeveralLoops(loops));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn participant_set(participants: &[u64]) -> Result<HashSet<u64>, MatchError> {
+    let mut everyone = HashSet::with_capacity(participants.len());
+    for uid in participants {
+        if !everyone.insert(*uid) {
+            return Err(MatchError::DuplicateParticipant(*uid));
+        }
+    }
+    Ok(everyone)
+}
+
+/// How many plain shuffles to try before falling back to an exhaustive search
+const SHUFFLE_ATTEMPTS: usize = 64;
+/// How many steps the single loop search may take before giving up
+const SEARCH_STEPS: usize = 1_000_000;
+/// Largest party drawn by interests. The weighted assignment takes cubic time, so bigger
+/// parties fall back to a plain shuffle.
+pub const PREFERENCE_LIMIT: usize = 300;
+/// How much each shared interest adds to a pair's score. Scores carry Gumbel noise, so every
+/// shared interest multiplies a pair's odds by about e^weight instead of deciding it.
+const INTEREST_WEIGHT: f64 = 1.0;
+/// Cost of a pair the rules forbid, far above any score a real pair can reach
+const FORBIDDEN: f64 = 1e9;
+
+/// Draws an assignment for `participants` that honors `rules`.
+///
+/// Everyone gives to as many different people as [`Rules::set_gifts_per_person`] asks for and receives as many gifts.
+/// Repeats of earlier draws are avoided where possible, dropping the oldest draws one at a time
+/// until an assignment exists. Only the hard rules can make the draw fail.
+///
+/// The result only depends on the order of `participants`, the rules and the state of `rng`,
+/// so a seeded rng always reproduces the same draw.
+pub fn draw<R: Rng + ?Sized>(
+    participants: &[u64],
+    rules: &Rules,
+    rng: &mut R,
+) -> Result<Assignment, MatchError> {
+    let needed = rules.gifts_per_person + 1;
+    if participants.len() < needed {
+        return Err(MatchError::NotEnoughParticipants {
+            needed,
+            got: participants.len(),
+        });
+    }
+    if rules.single_cycle && rules.gifts_per_person > 1 {
+        return Err(MatchError::SingleCycleWithSeveralGifts);
+    }
+    let everyone = participant_set(participants)?;
+    check_pins(&everyone, rules)?;
+    check_regions(participants, rules)?;
+    check_tiers(participants, rules)?;
+    let mut failure = MatchError::Unsatisfiable;
+    for depth in (0..=rules.history.len()).rev() {
+        let allowed = |giver, receiver| rules.allows_with_history(giver, receiver, depth);
+        let pairs = if rules.single_cycle {
+            match chain(participants, allowed, rng) {
+                Ok(order) => order
+                    .iter()
+                    .zip(order.iter().cycle().skip(1))
+                    .map(|(&giver, &receiver)| Pair { giver, receiver })
+                    .collect(),
+                Err(e) => {
+                    failure = e;
+                    continue;
+                }
+            }
+        } else {
+            let gifts = rules.gifts_per_person;
+            // Shuffles can't place several pins per giver, so only the search handles those
+            let shuffle = rules.pins.is_empty() || gifts == 1;
+            let prefer =
+                shuffle && !rules.interests.is_empty() && participants.len() <= PREFERENCE_LIMIT;
+            let Some(receivers) = prefer
+                .then(|| preferred(participants, gifts, &rules.interests, allowed, rng))
+                .flatten()
+                .or_else(|| {
+                    shuffle
+                        .then(|| shuffled(participants, gifts, allowed, rng))
+                        .flatten()
+                })
+                .or_else(|| search(participants, gifts, &rules.pins, allowed, rng))
+            else {
+                failure = MatchError::Unsatisfiable;
+                continue;
+            };
+            participants
+                .iter()
+                .zip(receivers)
+                .flat_map(|(&giver, receivers)| {
+                    receivers
+                        .into_iter()
+                        .map(move |receiver| Pair { giver, receiver })
+                })
+                .collect()
+        };
+        let assignment = Assignment {
+            pairs,
+            history_honored: depth,
+        };
+        assignment.validate(participants, rules)?;
+        return Ok(assignment);
+    }
+    Err(failure)
+}
+
+/// Takes `leaver` out of a finished draw by handing their receivers to their givers, so nobody
+/// else's target changes. Fails when that would break the rules, and only a redraw can help.
+pub fn splice(pairs: &[Pair], leaver: u64, rules: &Rules) -> Result<Assignment, MatchError> {
+    let givers = pairs
+        .iter()
+        .filter(|pair| pair.receiver == leaver)
+        .map(|pair| pair.giver)
+        .collect::<Vec<_>>();
+    let receivers = pairs
+        .iter()
+        .filter(|pair| pair.giver == leaver)
+        .map(|pair| pair.receiver)
+        .collect::<Vec<_>>();
+    if givers.is_empty() && receivers.is_empty() {
+        return Err(MatchError::UnknownParticipant(leaver));
+    }
+    let mut kept = pairs
+        .iter()
+        .filter(|pair| pair.giver != leaver && pair.receiver != leaver)
+        .copied()
+        .collect::<Vec<_>>();
+    let taken = kept.iter().copied().collect::<HashSet<_>>();
+    let fits = |giver: u64, receiver: u64| {
+        rules.allows(giver, receiver) && !taken.contains(&Pair { giver, receiver })
+    };
+    let handed = hand_over(&givers, &receivers, &fits).ok_or(MatchError::CannotSplice(leaver))?;
+    kept.extend(handed);
+    Ok(Assignment {
+        pairs: kept,
+        history_honored: 0,
+    })
+}
+
+/// Adds `newcomer` to a finished draw: a few randomly chosen givers give to the newcomer instead,
+/// and the newcomer takes over their old receivers. Everyone else keeps their target.
+pub fn insert<R: Rng + ?Sized>(
+    pairs: &[Pair],
+    newcomer: u64,
+    rules: &Rules,
+    rng: &mut R,
+) -> Result<Assignment, MatchError> {
+    if pairs
+        .iter()
+        .any(|pair| pair.giver == newcomer || pair.receiver == newcomer)
+    {
+        return Err(MatchError::DuplicateParticipant(newcomer));
+    }
+    let gifts = rules.gifts_per_person;
+    let fits =
+        |pair: &Pair| rules.allows(pair.giver, newcomer) && rules.allows(newcomer, pair.receiver);
+    let mut order = (0..pairs.len()).collect::<Vec<_>>();
+    for _ in 0..SHUFFLE_ATTEMPTS {
+        order.shuffle(rng);
+        let mut givers = HashSet::new();
+        let mut receivers = HashSet::new();
+        let mut chosen = Vec::new();
+        for &i in &order {
+            if chosen.len() == gifts {
+                break;
+            }
+            let pair = pairs[i];
+            if fits(&pair) && !givers.contains(&pair.giver) && !receivers.contains(&pair.receiver) {
+                givers.insert(pair.giver);
+                receivers.insert(pair.receiver);
+                chosen.push(i);
+            }
+        }
+        if chosen.len() < gifts {
+            continue;
+        }
+        let mut grown = pairs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !chosen.contains(i))
+            .map(|(_, &pair)| pair)
+            .collect::<Vec<_>>();
+        for i in chosen {
+            grown.push(Pair {
+                giver: pairs[i].giver,
+                receiver: newcomer,
+            });
+            grown.push(Pair {
+                giver: newcomer,
+                receiver: pairs[i].receiver,
+            });
+        }
+        return Ok(Assignment {
+            pairs: grown,
+            history_honored: 0,
+        });
+    }
+    Err(MatchError::CannotInsert(newcomer))
+}
+
+/// Gives each of `givers` one of `receivers`, backtracking over the handful of options
+fn hand_over(
+    givers: &[u64],
+    receivers: &[u64],
+    fits: &impl Fn(u64, u64) -> bool,
+) -> Option<Vec<Pair>> {
+    let Some((&giver, rest)) = givers.split_first() else {
+        return Some(Vec::new());
+    };
+    for (i, &receiver) in receivers.iter().enumerate() {
+        if !fits(giver, receiver) {
+            continue;
+        }
+        let mut others = receivers.to_vec();
+        others.remove(i);
+        if let Some(mut pairs) = hand_over(rest, &others, fits) {
+            pairs.push(Pair { giver, receiver });
+            return Some(pairs);
+        }
+    }
+    None
+}
+
+/// Summary of a batch of trial draws, which says nothing about who drew whom
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preview {
+    pub runs: usize,
+    pub fewest_loops: usize,
+    pub most_loops: usize,
+    pub total_loops: usize,
+    pub single_loop_runs: usize,
+    pub fewest_history_honored: usize,
+}
+
+/// Runs `runs` draws and keeps only their statistics. Fails with the first draw that fails,
+/// which can only happen when the rules can't be satisfied at all.
+pub fn preview<R: Rng + ?Sized>(
+    participants: &[u64],
+    rules: &Rules,
+    runs: usize,
+    rng: &mut R,
+) -> Result<Preview, MatchError> {
+    let mut preview = Preview {
+        runs,
+        fewest_loops: usize::MAX,
+        most_loops: 0,
+        total_loops: 0,
+        single_loop_runs: 0,
+        fewest_history_honored: rules.history_len(),
+    };
+    for _ in 0..runs {
+        let assignment = draw(participants, rules, rng)?;
+        let loops = assignment.cycles().len();
+        preview.fewest_loops = preview.fewest_loops.min(loops);
+        preview.most_loops = preview.most_loops.max(loops);
+        preview.total_loops += loops;
+        preview.single_loop_runs += usize::from(loops == 1);
+        preview.fewest_history_honored = preview
+            .fewest_history_honored
+            .min(assignment.history_honored());
+    }
+    preview.fewest_loops = preview.fewest_loops.min(preview.most_loops);
+    Ok(preview)
+}
+
+/// Finds regions whose members can't all give their gifts without leaving it
+fn check_regions(participants: &[u64], rules: &Rules) -> Result<(), MatchError> {
+    let mut sizes = HashMap::<&str, usize>::new();
+    for uid in participants {
+        if let Some(region) = rules.regions.get(uid) {
+            *sizes.entry(region).or_default() += 1;
+        }
+    }
+    let mut too_small = participants
+        .iter()
+        .filter(|uid| !rules.ships_abroad.contains(uid))
+        .filter_map(|uid| rules.regions.get(uid))
+        .filter(|region| sizes[region.as_str()] <= rules.gifts_per_person)
+        .cloned()
+        .collect::<Vec<_>>();
+    too_small.sort();
+    too_small.dedup();
+    if too_small.is_empty() {
+        Ok(())
+    } else {
+        Err(MatchError::RegionsTooSmall(too_small))
+    }
+}
+
+/// Finds budget tiers with too few people to give each other their gifts
+fn check_tiers(participants: &[u64], rules: &Rules) -> Result<(), MatchError> {
+    let mut sizes = HashMap::<u32, usize>::new();
+    for uid in participants {
+        if let Some(&tier) = rules.tiers.get(uid) {
+            *sizes.entry(tier).or_default() += 1;
+        }
+    }
+    let mut too_small = sizes
+        .into_iter()
+        .filter(|&(_, size)| size <= rules.gifts_per_person)
+        .map(|(tier, _)| tier)
+        .collect::<Vec<_>>();
+    too_small.sort();
+    if too_small.is_empty() {
+        Ok(())
+    } else {
+        Err(MatchError::TiersTooSmall(too_small))
+    }
+}
+
+/// Makes sure the pins fit the other rules before any drawing is attempted
+fn check_pins(everyone: &HashSet<u64>, rules: &Rules) -> Result<(), MatchError> {
+    let mut given = HashMap::<u64, usize>::new();
+    let mut received = HashMap::<u64, usize>::new();
+    for &(giver, receiver) in &rules.pins {
+        for uid in [giver, receiver] {
+            if !everyone.contains(&uid) {
+                return Err(MatchError::UnknownParticipant(uid));
+            }
+        }
+        if giver == receiver {
+            return Err(MatchError::SelfGift(giver));
+        }
+        if !rules.allows(giver, receiver) {
+            return Err(MatchError::Forbidden(giver, receiver));
+        }
+        let given = given.entry(giver).or_default();
+        *given += 1;
+        if *given > rules.gifts_per_person {
+            return Err(MatchError::TooManyPins(giver));
+        }
+        let received = received.entry(receiver).or_default();
+        *received += 1;
+        if *received > rules.gifts_per_person {
+            return Err(MatchError::TooManyPins(receiver));
+        }
+    }
+    Ok(())
+}
+
+/// Early-refusal sampling, which is fast whenever the rules are loose.
+///
+/// Each giver's receivers come from `gifts` layers, and each layer is a Fisher-Yates shuffle that
+/// starts over the moment a giver is handed someone they may not have, instead of finishing a
+/// doomed permutation first. Without exclusions a layer needs about e passes that mostly stop
+/// early, so the work stays linear in the number of participants.
+fn shuffled<R: Rng + ?Sized>(
+    participants: &[u64],
+    gifts: usize,
+    allowed: impl Fn(u64, u64) -> bool,
+    rng: &mut R,
+) -> Option<Vec<Vec<u64>>> {
+    let n = participants.len();
+    let mut receivers = vec![Vec::with_capacity(gifts); n];
+    let mut layer = participants.to_vec();
+    for _ in 0..gifts {
+        let mut placed = false;
+        'attempt: for _ in 0..SHUFFLE_ATTEMPTS {
+            for i in (0..n).rev() {
+                layer.swap(i, rng.random_range(0..=i));
+                if !allowed(participants[i], layer[i]) || receivers[i].contains(&layer[i]) {
+                    continue 'attempt;
+                }
+            }
+            placed = true;
+            break;
+        }
+        if !placed {
+            return None;
+        }
+        for (receivers, &receiver) in receivers.iter_mut().zip(&layer) {
+            receivers.push(receiver);
+        }
+    }
+    Some(receivers)
+}
+
+/// Picks an assignment weighted towards shared interests, one layer of gifts at a time.
+///
+/// Every pair's score is its shared interests times [`INTEREST_WEIGHT`] plus Gumbel noise, and
+/// the best-scoring assignment wins. Like a softmax, sharing raises a pair's odds, but plenty of
+/// draws still pass them over, so knowing everyone's tags doesn't tell who drew whom.
+fn preferred<R: Rng + ?Sized>(
+    participants: &[u64],
+    gifts: usize,
+    interests: &HashMap<u64, HashSet<String>>,
+    allowed: impl Fn(u64, u64) -> bool,
+    rng: &mut R,
+) -> Option<Vec<Vec<u64>>> {
+    let n = participants.len();
+    let shared = |giver: &u64, receiver: &u64| match (interests.get(giver), interests.get(receiver))
+    {
+        (Some(a), Some(b)) => a.intersection(b).count() as f64,
+        _ => 0.0,
+    };
+    let mut receivers = vec![Vec::with_capacity(gifts); n];
+    for _ in 0..gifts {
+        let cost = participants
+            .iter()
+            .zip(&receivers)
+            .map(|(giver, taken)| {
+                participants
+                    .iter()
+                    .map(|receiver| {
+                        if !allowed(*giver, *receiver) || taken.contains(receiver) {
+                            FORBIDDEN
+                        } else {
+                            let noise = -(-rng.random_range(f64::EPSILON..1.0).ln()).ln();
+                            -(INTEREST_WEIGHT * shared(giver, receiver) + noise)
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let columns = hungarian(&cost);
+        for (row, &column) in columns.iter().enumerate() {
+            if cost[row][column] >= FORBIDDEN {
+                return None;
+            }
+            receivers[row].push(participants[column]);
+        }
+    }
+    Some(receivers)
+}
+
+/// Minimum-cost perfect assignment of rows to columns on a square matrix, using the Hungarian
+/// method with potentials. Returns each row's column.
+fn hungarian(cost: &[Vec<f64>]) -> Vec<usize> {
+    let n = cost.len();
+    // Index 0 is a virtual column that the row being added starts from
+    let mut row_potential = vec![0.0; n + 1];
+    let mut column_potential = vec![0.0; n + 1];
+    let mut row_of = vec![0; n + 1];
+    let mut previous = vec![0; n + 1];
+    for row in 1..=n {
+        row_of[0] = row;
+        let mut column = 0;
+        let mut slack = vec![f64::INFINITY; n + 1];
+        let mut used = vec![false; n + 1];
+        loop {
+            used[column] = true;
+            let current = row_of[column];
+            let mut delta = f64::INFINITY;
+            let mut next = 0;
+            for j in 1..=n {
+                if used[j] {
+                    continue;
+                }
+                let reduced =
+                    cost[current - 1][j - 1] - row_potential[current] - column_potential[j];
+                if reduced < slack[j] {
+                    slack[j] = reduced;
+                    previous[j] = column;
+                }
+                if slack[j] < delta {
+                    delta = slack[j];
+                    next = j;
+                }
+            }
+            for j in 0..=n {
+                if used[j] {
+                    row_potential[row_of[j]] += delta;
+                    column_potential[j] -= delta;
+                } else {
+                    slack[j] -= delta;
+                }
+            }
+            column = next;
+            if row_of[column] == 0 {
+                break;
+            }
+        }
+        while column != 0 {
+            let before = previous[column];
+            row_of[column] = row_of[before];
+            column = before;
+        }
+    }
+    let mut columns = vec![0; n];
+    for j in 1..=n {
+        columns[row_of[j] - 1] = j - 1;
+    }
+    columns
+}
+
+/// Gives every giver `gifts` receivers (and every receiver `gifts` givers) with augmenting paths.
+///
+/// Pinned pairs are placed first and never moved. Givers and receivers are visited in a random
+/// order so the result is still unpredictable, and returning `None` proves that no valid
+/// assignment exists.
+fn search<R: Rng + ?Sized>(
+    participants: &[u64],
+    gifts: usize,
+    pins: &[(u64, u64)],
+    allowed: impl Fn(u64, u64) -> bool,
+    rng: &mut R,
+) -> Option<Vec<Vec<u64>>> {
+    let n = participants.len();
+    let mut receiver_order = (0..n).collect::<Vec<_>>();
+    receiver_order.shuffle(rng);
+    let mut giver_order = (0..n).collect::<Vec<_>>();
+    giver_order.shuffle(rng);
+    let mut receivers_of = vec![Vec::<usize>::with_capacity(gifts); n];
+    let mut givers_of = vec![Vec::<usize>::with_capacity(gifts); n];
+    // The giver that reached each receiver, and the full receiver each giver was reached through
+    let mut reached_from = vec![0usize; n];
+    let mut reached_via = vec![0usize; n];
+    let mut receiver_seen = vec![usize::MAX; n];
+    let mut giver_seen = vec![usize::MAX; n];
+    let index_of = participants
+        .iter()
+        .enumerate()
+        .map(|(i, &uid)| (uid, i))
+        .collect::<HashMap<_, _>>();
+    for (giver, receiver) in pins {
+        receivers_of[index_of[giver]].push(index_of[receiver]);
+        givers_of[index_of[receiver]].push(index_of[giver]);
+    }
+    let pinned = |giver: usize, receiver: usize| {
+        pins.contains(&(participants[giver], participants[receiver]))
+    };
+    let mut round = 0;
+    for pass in 0..gifts {
+        for &start in &giver_order {
+            if receivers_of[start].len() > pass {
+                continue;
+            }
+            round += 1;
+            giver_seen[start] = round;
+            let mut queue = VecDeque::from([start]);
+            let mut free_receiver = None;
+            'bfs: while let Some(giver) = queue.pop_front() {
+                for &receiver in &receiver_order {
+                    if receiver_seen[receiver] == round
+                        || receivers_of[giver].contains(&receiver)
+                        || !allowed(participants[giver], participants[receiver])
+                    {
+                        continue;
+                    }
+                    receiver_seen[receiver] = round;
+                    reached_from[receiver] = giver;
+                    if givers_of[receiver].len() < gifts {
+                        free_receiver = Some(receiver);
+                        break 'bfs;
+                    }
+                    for &holder in &givers_of[receiver] {
+                        if giver_seen[holder] != round && !pinned(holder, receiver) {
+                            giver_seen[holder] = round;
+                            reached_via[holder] = receiver;
+                            queue.push_back(holder);
+                        }
+                    }
+                }
+            }
+            let mut receiver = free_receiver?;
+            loop {
+                let giver = reached_from[receiver];
+                receivers_of[giver].push(receiver);
+                givers_of[receiver].push(giver);
+                if giver == start {
+                    break;
+                }
+                let previous = reached_via[giver];
+                receivers_of[giver].retain(|&r| r != previous);
+                givers_of[previous].retain(|&g| g != giver);
+                receiver = previous;
+            }
+        }
+    }
+    Some(
+        receivers_of
+            .into_iter()
+            .map(|receivers| receivers.into_iter().map(|r| participants[r]).collect())
+            .collect(),
+    )
+}
+
+/// Finds one loop through every participant, returned as givers in giving order.
+///
+/// Random orders are tried first; after that a backtracking search either finds a loop,
+/// proves none exists, or gives up after [`SEARCH_STEPS`].
+fn chain<R: Rng + ?Sized>(
+    participants: &[u64],
+    allowed: impl Fn(u64, u64) -> bool,
+    rng: &mut R,
+) -> Result<Vec<u64>, MatchError> {
+    let n = participants.len();
+    let mut order = participants.to_vec();
+    for _ in 0..SHUFFLE_ATTEMPTS {
+        order.shuffle(rng);
+        if order
+            .iter()
+            .zip(order.iter().cycle().skip(1))
+            .all(|(&giver, &receiver)| allowed(giver, receiver))
+        {
+            return Ok(order);
+        }
+    }
+    // Every loop passes through every participant, so the search can start anywhere
+    let start = rng.random_range(0..n);
+    let options_from = |giver: usize, used: &[bool], rng: &mut R| {
+        let mut options = (0..n)
+            .filter(|&receiver| {
+                !used[receiver] && allowed(participants[giver], participants[receiver])
+            })
+            .collect::<Vec<_>>();
+        options.shuffle(rng);
+        options
+    };
+    let mut used = vec![false; n];
+    used[start] = true;
+    let mut path = vec![start];
+    let mut stack = vec![options_from(start, &used, rng)];
+    let mut steps = 0;
+    while let Some(options) = stack.last_mut() {
+        steps += 1;
+        if steps > SEARCH_STEPS {
+            return Err(MatchError::SearchLimit);
+        }
+        match options.pop() {
+            Some(next) => {
+                path.push(next);
+                used[next] = true;
+                if path.len() == n {
+                    if allowed(participants[next], participants[start]) {
+                        return Ok(path.into_iter().map(|i| participants[i]).collect());
+                    }
+                    path.pop();
+                    used[next] = false;
+                } else {
+                    stack.push(options_from(next, &used, rng));
+                }
+            }
+            None => {
+                stack.pop();
+                if let Some(last) = path.pop() {
+                    used[last] = false;
+                }
+            }
+        }
+    }
+    Err(MatchError::Unsatisfiable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn uids(n: u64) -> Vec<u64> {
+        (1..=n).collect()
+    }
+
+    #[test]
+    fn draws_are_valid_derangements() {
+        for seed in 0..200 {
+            let participants = uids(2 + seed % 9);
+            let assignment = draw(
+                &participants,
+                &Rules::default(),
+                &mut ChaCha20Rng::seed_from_u64(seed),
+            )
+            .unwrap();
+            assert_eq!(assignment.pairs().len(), participants.len());
+            assignment
+                .validate(&participants, &Rules::default())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn same_seed_same_draw() {
+        let participants = uids(25);
+        let a = draw(
+            &participants,
+            &Rules::default(),
+            &mut ChaCha20Rng::seed_from_u64(7),
+        )
+        .unwrap();
+        let b = draw(
+            &participants,
+            &Rules::default(),
+            &mut ChaCha20Rng::seed_from_u64(7),
+        )
+        .unwrap();
+        let c = draw(
+            &participants,
+            &Rules::default(),
+            &mut ChaCha20Rng::seed_from_u64(8),
+        )
+        .unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn two_people_swap() {
+        let assignment = draw(
+            &[10, 20],
+            &Rules::default(),
+            &mut ChaCha20Rng::seed_from_u64(0),
+        )
+        .unwrap();
+        assert_eq!(
+            assignment.pairs(),
+            &[
+                Pair {
+                    giver: 10,
+                    receiver: 20
+                },
+                Pair {
+                    giver: 20,
+                    receiver: 10
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_tiny_and_duplicate_lists() {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        assert_eq!(
+            draw(&[], &Rules::default(), &mut rng),
+            Err(MatchError::NotEnoughParticipants { needed: 2, got: 0 })
+        );
+        assert_eq!(
+            draw(&[1], &Rules::default(), &mut rng),
+            Err(MatchError::NotEnoughParticipants { needed: 2, got: 1 })
+        );
+        assert_eq!(
+            draw(&[1, 2, 1], &Rules::default(), &mut rng),
+            Err(MatchError::DuplicateParticipant(1))
+        );
+    }
+
+    #[test]
+    fn validate_catches_bad_assignments() {
+        let participants = uids(3);
+        let pairs = |p: &[(u64, u64)]| Assignment {
+            pairs: p
+                .iter()
+                .map(|&(giver, receiver)| Pair { giver, receiver })
+                .collect(),
+            history_honored: 0,
+        };
+        assert_eq!(
+            pairs(&[(1, 1), (2, 3), (3, 2)]).validate(&participants, &Rules::default()),
+            Err(MatchError::SelfGift(1))
+        );
+        assert_eq!(
+            pairs(&[(1, 2), (2, 1), (3, 2)]).validate(&participants, &Rules::default()),
+            Err(MatchError::NotReceiving(2))
+        );
+        assert_eq!(
+            pairs(&[(1, 2), (2, 1)]).validate(&participants, &Rules::default()),
+            Err(MatchError::NotGiving(3))
+        );
+        assert_eq!(
+            pairs(&[(1, 2), (2, 3), (3, 4)]).validate(&participants, &Rules::default()),
+            Err(MatchError::UnknownParticipant(4))
+        );
+        pairs(&[(1, 2), (2, 3), (3, 1)])
+            .validate(&participants, &Rules::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn exclusions_are_honored() {
+        let participants = uids(6);
+        let mut rules = Rules::default();
+        rules.exclude(1, 2);
+        rules.exclude(3, 4);
+        rules.exclude(5, 1);
+        for seed in 0..200 {
+            let assignment =
+                draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+            assignment.validate(&participants, &rules).unwrap();
+        }
+    }
+
+    #[test]
+    fn tight_exclusions_fall_back_to_search() {
+        // Only 1 -> 4, 2 -> 3, 3 -> 2, 4 -> 1 survives these exclusions
+        let participants = uids(4);
+        let mut rules = Rules::default();
+        rules.exclude(1, 3);
+        rules.exclude(2, 4);
+        rules.exclude(1, 2);
+        let seen = (0..50)
+            .map(|seed| {
+                let assignment =
+                    draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+                assignment.validate(&participants, &rules).unwrap();
+                assignment
+            })
+            .collect::<Vec<_>>();
+        assert!(seen.iter().all(|a| a == &seen[0]));
+    }
+
+    #[test]
+    fn impossible_exclusions_are_reported() {
+        let participants = uids(3);
+        let mut rules = Rules::default();
+        rules.exclude(1, 2);
+        rules.exclude(1, 3);
+        assert_eq!(
+            draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(0)),
+            Err(MatchError::Unsatisfiable)
+        );
+        let mut couples = Rules::default();
+        couples.exclude(1, 2);
+        assert_eq!(
+            draw(&[1, 2], &couples, &mut ChaCha20Rng::seed_from_u64(0)),
+            Err(MatchError::Unsatisfiable)
+        );
+    }
+
+    #[test]
+    fn search_finds_what_shuffles_miss() {
+        let participants = uids(40);
+        let mut rules = Rules::default();
+        // The first ten people may only swap with their neighbour, which a shuffle won't stumble on
+        for a in 1..=10 {
+            for b in 1..=40 {
+                if a != b && b != if a % 2 == 1 { a + 1 } else { a - 1 } {
+                    rules.exclude(a, b);
+                }
+            }
+        }
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        assert_eq!(
+            shuffled(&participants, 1, |g, r| rules.allows(g, r), &mut rng),
+            None
+        );
+        let assignment = draw(&participants, &rules, &mut rng).unwrap();
+        assignment.validate(&participants, &rules).unwrap();
+    }
+
+    #[test]
+    fn earlier_draws_are_not_repeated() {
+        let participants = uids(8);
+        let last_year = draw(
+            &participants,
+            &Rules::default(),
+            &mut ChaCha20Rng::seed_from_u64(1),
+        )
+        .unwrap();
+        let mut rules = Rules::default();
+        rules.avoid_repeats(last_year.pairs().iter().map(|p| (p.giver, p.receiver)));
+        for seed in 0..100 {
+            let assignment =
+                draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+            assert_eq!(assignment.history_honored(), 1);
+            assert!(
+                assignment
+                    .pairs()
+                    .iter()
+                    .all(|pair| !last_year.pairs().contains(pair))
+            );
+        }
+    }
+
+    #[test]
+    fn oldest_history_is_dropped_first() {
+        // With three people there are only two derangements, so two years of history can't both hold
+        let participants = uids(3);
+        let mut rules = Rules::default();
+        rules.avoid_repeats([(1, 2), (2, 3), (3, 1)]);
+        rules.avoid_repeats([(1, 3), (3, 2), (2, 1)]);
+        let assignment = draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(0)).unwrap();
+        assert_eq!(assignment.history_honored(), 1);
+        assert!(assignment.pairs().contains(&Pair {
+            giver: 1,
+            receiver: 3
+        }));
+    }
+
+    #[test]
+    fn history_yields_to_exclusions() {
+        // The exclusions leave exactly one assignment, which also happens to be last year's
+        let participants = uids(4);
+        let mut rules = Rules::default();
+        rules.exclude(1, 3);
+        rules.exclude(2, 4);
+        rules.exclude(1, 2);
+        rules.avoid_repeats([(1, 4), (2, 3), (3, 2), (4, 1)]);
+        let assignment = draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(0)).unwrap();
+        assert_eq!(assignment.history_honored(), 0);
+        assignment.validate(&participants, &rules).unwrap();
+    }
+
+    #[test]
+    fn cycles_follow_the_gift_chain() {
+        let assignment = Assignment {
+            pairs: [(1, 2), (2, 1), (3, 5), (4, 3), (5, 4)]
+                .iter()
+                .map(|&(giver, receiver)| Pair { giver, receiver })
+                .collect(),
+            history_honored: 0,
+        };
+        assert_eq!(assignment.cycles(), vec![vec![1, 2], vec![3, 5, 4]]);
+    }
+
+    #[test]
+    fn single_cycle_draws_form_one_loop() {
+        let mut rules = Rules::default();
+        rules.require_single_cycle();
+        for seed in 0..100 {
+            let participants = uids(2 + seed % 12);
+            let assignment =
+                draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+            assignment.validate(&participants, &rules).unwrap();
+            assert_eq!(assignment.cycles().len(), 1);
+            assert_eq!(assignment.cycles()[0].len(), participants.len());
+        }
+    }
+
+    #[test]
+    fn single_cycle_search_honors_exclusions() {
+        let participants = uids(12);
+        let mut rules = Rules::default();
+        rules.require_single_cycle();
+        // Odd people may only give to even people and the other way around, except for 1 and 2
+        for a in 1..=12u64 {
+            for b in (a + 1)..=12 {
+                if a % 2 == b % 2 || (a, b) == (1, 2) {
+                    rules.exclude(a, b);
+                }
+            }
+        }
+        for seed in 0..20 {
+            let assignment =
+                draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+            assignment.validate(&participants, &rules).unwrap();
+        }
+    }
+
+    #[test]
+    fn two_couples_cannot_form_one_loop() {
+        // Only 1 <-> 2 and 3 <-> 4 are allowed, which is a valid draw with two loops
+        let participants = uids(4);
+        let mut rules = Rules::default();
+        for (a, b) in [(1, 3), (1, 4), (2, 3), (2, 4)] {
+            rules.exclude(a, b);
+        }
+        let assignment = draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(0)).unwrap();
+        assert_eq!(assignment.cycles().len(), 2);
+        rules.require_single_cycle();
+        assert_eq!(
+            draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(0)),
+            Err(MatchError::Unsatisfiable)
+        );
+        assert_eq!(
+            assignment.validate(&participants, &rules),
+            Err(MatchError::SeveralLoops(2))
+        );
+    }
+
+    #[test]
+    fn several_gifts_per_person() {
+        for gifts in 2..=4 {
+            let mut rules = Rules::default();
+            rules.set_gifts_per_person(gifts);
+            for seed in 0..50 {
+                let participants = uids(gifts as u64 + 1 + seed % 8);
+                let assignment =
+                    draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+                assert_eq!(assignment.pairs().len(), participants.len() * gifts);
+                assignment.validate(&participants, &rules).unwrap();
+                assert!(assignment.cycles().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn several_gifts_with_exclusions_use_the_search() {
+        // Everyone but 1 and 2 may give to anyone, so both of them must cover the rest
+        let participants = uids(5);
+        let mut rules = Rules::default();
+        rules.set_gifts_per_person(3);
+        rules.exclude(1, 2);
+        for seed in 0..50 {
+            let assignment =
+                draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+            assignment.validate(&participants, &rules).unwrap();
+        }
+        rules.exclude(1, 3);
+        assert_eq!(
+            draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(0)),
+            Err(MatchError::Unsatisfiable)
+        );
+    }
+
+    #[test]
+    fn several_gifts_need_enough_people() {
+        let mut rules = Rules::default();
+        rules.set_gifts_per_person(3);
+        assert_eq!(
+            draw(&uids(3), &rules, &mut ChaCha20Rng::seed_from_u64(0)),
+            Err(MatchError::NotEnoughParticipants { needed: 4, got: 3 })
+        );
+        rules.require_single_cycle();
+        assert_eq!(
+            draw(&uids(6), &rules, &mut ChaCha20Rng::seed_from_u64(0)),
+            Err(MatchError::SingleCycleWithSeveralGifts)
+        );
+    }
+
+    #[test]
+    fn validate_catches_duplicate_pairs() {
+        let mut rules = Rules::default();
+        rules.set_gifts_per_person(2);
+        let assignment = Assignment {
+            pairs: [(1, 2), (1, 2), (2, 1), (2, 3), (3, 1), (3, 3)]
+                .iter()
+                .map(|&(giver, receiver)| Pair { giver, receiver })
+                .collect(),
+            history_honored: 0,
+        };
+        assert_eq!(
+            assignment.validate(&uids(3), &rules),
+            Err(MatchError::DuplicatePair(1, 2))
+        );
+    }
+
+    #[test]
+    fn pins_are_kept() {
+        let participants = uids(10);
+        let mut rules = Rules::default();
+        rules.pin(3, 7);
+        rules.pin(7, 1);
+        rules.exclude(1, 2);
+        for seed in 0..100 {
+            let assignment =
+                draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+            assignment.validate(&participants, &rules).unwrap();
+        }
+        rules.require_single_cycle();
+        for seed in 0..100 {
+            let assignment =
+                draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+            assignment.validate(&participants, &rules).unwrap();
+        }
+    }
+
+    #[test]
+    fn pins_with_several_gifts() {
+        let participants = uids(6);
+        let mut rules = Rules::default();
+        rules.set_gifts_per_person(2);
+        rules.pin(1, 2);
+        rules.pin(1, 3);
+        rules.pin(4, 3);
+        for seed in 0..100 {
+            let assignment =
+                draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+            assignment.validate(&participants, &rules).unwrap();
+        }
+        rules.pin(1, 4);
+        assert_eq!(
+            draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(0)),
+            Err(MatchError::TooManyPins(1))
+        );
+    }
+
+    #[test]
+    fn pins_that_strand_everyone_else_are_rejected() {
+        let participants = uids(3);
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let mut rules = Rules::default();
+        rules.pin(1, 2);
+        rules.pin(2, 1);
+        assert_eq!(
+            draw(&participants, &rules, &mut rng),
+            Err(MatchError::Unsatisfiable)
+        );
+        let mut rules = Rules::default();
+        rules.exclude(1, 2);
+        rules.pin(1, 2);
+        assert_eq!(
+            draw(&participants, &rules, &mut rng),
+            Err(MatchError::Forbidden(1, 2))
+        );
+        let mut rules = Rules::default();
+        rules.pin(1, 4);
+        assert_eq!(
+            draw(&participants, &rules, &mut rng),
+            Err(MatchError::UnknownParticipant(4))
+        );
+        // A pinned swap would split the single loop
+        let mut rules = Rules::default();
+        rules.require_single_cycle();
+        rules.pin(1, 2);
+        rules.pin(2, 1);
+        assert_eq!(
+            draw(&uids(4), &rules, &mut rng),
+            Err(MatchError::Unsatisfiable)
+        );
+    }
+
+    #[test]
+    fn pins_beat_history() {
+        let participants = uids(5);
+        let mut rules = Rules::default();
+        rules.avoid_repeats([(1, 2)]);
+        rules.pin(1, 2);
+        let assignment = draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(0)).unwrap();
+        assert_eq!(assignment.history_honored(), 1);
+        assignment.validate(&participants, &rules).unwrap();
+    }
+
+    #[test]
+    fn previews_only_report_stats() {
+        let participants = uids(8);
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        let stats = preview(&participants, &Rules::default(), 200, &mut rng).unwrap();
+        assert_eq!(stats.runs, 200);
+        assert!(stats.fewest_loops >= 1 && stats.most_loops <= 4);
+        assert!(stats.most_loops > stats.fewest_loops);
+        assert!(stats.single_loop_runs > 0 && stats.single_loop_runs < 200);
+
+        let mut rules = Rules::default();
+        rules.require_single_cycle();
+        let stats = preview(&participants, &rules, 50, &mut rng).unwrap();
+        assert_eq!((stats.fewest_loops, stats.most_loops), (1, 1));
+        assert_eq!(stats.single_loop_runs, 50);
+
+        let mut rules = Rules::default();
+        rules.exclude(1, 2);
+        assert_eq!(
+            preview(&[1, 2], &rules, 10, &mut rng),
+            Err(MatchError::Unsatisfiable)
+        );
+    }
+
+    #[test]
+    fn leavers_are_spliced_out_of_their_loop() {
+        let pairs =
+            [(1, 2), (2, 3), (3, 4), (4, 1)].map(|(giver, receiver)| Pair { giver, receiver });
+        let mut rules = Rules::default();
+        rules.require_single_cycle();
+        let spliced = splice(&pairs, 3, &rules).unwrap();
+        assert!(spliced.pairs().contains(&Pair {
+            giver: 2,
+            receiver: 4
+        }));
+        spliced.validate(&[1, 2, 4], &rules).unwrap();
+        assert_eq!(
+            splice(&pairs, 5, &rules),
+            Err(MatchError::UnknownParticipant(5))
+        );
+        rules.exclude(2, 4);
+        assert_eq!(splice(&pairs, 3, &rules), Err(MatchError::CannotSplice(3)));
+        // The leaver's giver would be left giving to themselves
+        let swap =
+            [(1, 2), (2, 1), (3, 4), (4, 3)].map(|(giver, receiver)| Pair { giver, receiver });
+        assert_eq!(
+            splice(&swap, 2, &Rules::default()),
+            Err(MatchError::CannotSplice(2))
+        );
+    }
+
+    #[test]
+    fn several_gifts_are_handed_over_without_duplicates() {
+        let participants = uids(7);
+        let mut rules = Rules::default();
+        rules.set_gifts_per_person(2);
+        for seed in 0..50 {
+            let assignment =
+                draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+            match splice(assignment.pairs(), 7, &rules) {
+                Ok(spliced) => spliced.validate(&uids(6), &rules).unwrap(),
+                Err(e) => assert_eq!(e, MatchError::CannotSplice(7)),
+            }
+        }
+    }
+
+    #[test]
+    fn newcomers_are_inserted_into_an_existing_loop() {
+        let mut rng = ChaCha20Rng::seed_from_u64(5);
+        let mut rules = Rules::default();
+        rules.require_single_cycle();
+        let assignment = draw(&uids(4), &rules, &mut rng).unwrap();
+        let grown = insert(assignment.pairs(), 5, &rules, &mut rng).unwrap();
+        grown.validate(&uids(5), &rules).unwrap();
+        let changed = assignment
+            .pairs()
+            .iter()
+            .filter(|pair| !grown.pairs().contains(pair))
+            .count();
+        assert_eq!(changed, 1);
+        assert_eq!(
+            insert(grown.pairs(), 5, &rules, &mut rng),
+            Err(MatchError::DuplicateParticipant(5))
+        );
+        for uid in 1..=4 {
+            rules.exclude(uid, 6);
+        }
+        assert_eq!(
+            insert(assignment.pairs(), 6, &rules, &mut rng),
+            Err(MatchError::CannotInsert(6))
+        );
+    }
+
+    #[test]
+    fn newcomers_take_over_several_gifts() {
+        let mut rng = ChaCha20Rng::seed_from_u64(6);
+        let mut rules = Rules::default();
+        rules.set_gifts_per_person(3);
+        rules.exclude(1, 9);
+        let assignment = draw(&uids(8), &rules, &mut rng).unwrap();
+        let grown = insert(assignment.pairs(), 9, &rules, &mut rng).unwrap();
+        grown.validate(&uids(9), &rules).unwrap();
+    }
+
+    #[test]
+    fn large_parties_are_drawn_by_shuffling() {
+        let participants = uids(10_000);
+        let mut rng = ChaCha20Rng::seed_from_u64(13);
+        let receivers = shuffled(
+            &participants,
+            1,
+            |giver, receiver| giver != receiver,
+            &mut rng,
+        )
+        .expect("a plain derangement should never need the search");
+        assert!(
+            participants
+                .iter()
+                .zip(&receivers)
+                .all(|(giver, receivers)| receivers[0] != *giver)
+        );
+        let mut rules = Rules::default();
+        rules.set_gifts_per_person(3);
+        draw(&participants, &rules, &mut rng)
+            .unwrap()
+            .validate(&participants, &rules)
+            .unwrap();
+    }
+
+    #[test]
+    fn shuffled_derangements_are_uniform() {
+        // Three people have exactly two derangements, which should come up equally often
+        let mut rng = ChaCha20Rng::seed_from_u64(21);
+        let mut counts = HashMap::new();
+        for _ in 0..4000 {
+            let receivers =
+                shuffled(&[1, 2, 3], 1, |giver, receiver| giver != receiver, &mut rng).unwrap();
+            *counts.entry(receivers).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 2);
+        assert!(counts.values().all(|&count| (1800..2200).contains(&count)));
+    }
+
+    #[test]
+    fn givers_stay_in_their_region_unless_they_ship_abroad() {
+        let participants = uids(9);
+        let mut rules = Rules::default();
+        for uid in 1..=4 {
+            rules.set_region(uid, "Europe", false);
+        }
+        for uid in 5..=7 {
+            rules.set_region(uid, "north america ", false);
+        }
+        rules.set_region(8, "Oceania", true);
+        rules.set_region(9, "Oceania", true);
+        assert!(!rules.allows(1, 5));
+        assert!(rules.allows(5, 6));
+        assert!(rules.allows(8, 1));
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        for _ in 0..50 {
+            let assignment = draw(&participants, &rules, &mut rng).unwrap();
+            assignment.validate(&participants, &rules).unwrap();
+        }
+        // Someone alone in their region who can't ship abroad has nobody to give to
+        rules.set_region(10, "Asia", false);
+        rules.set_region(11, "Africa", false);
+        assert_eq!(
+            draw(&uids(11), &rules, &mut rng),
+            Err(MatchError::RegionsTooSmall(vec![
+                "africa".to_owned(),
+                "asia".to_owned()
+            ]))
+        );
+    }
+
+    #[test]
+    fn budget_tiers_are_never_mixed() {
+        let participants = uids(8);
+        let mut rules = Rules::default();
+        for uid in 1..=3 {
+            rules.set_tier(uid, 10);
+        }
+        for uid in 4..=8 {
+            rules.set_tier(uid, 25);
+        }
+        let mut rng = ChaCha20Rng::seed_from_u64(4);
+        for _ in 0..50 {
+            let assignment = draw(&participants, &rules, &mut rng).unwrap();
+            assignment.validate(&participants, &rules).unwrap();
+            assert!(
+                assignment
+                    .pairs()
+                    .iter()
+                    .all(|pair| (pair.giver <= 3) == (pair.receiver <= 3))
+            );
+        }
+        rules.set_tier(9, 50);
+        assert_eq!(
+            draw(&uids(9), &rules, &mut rng),
+            Err(MatchError::TiersTooSmall(vec![50]))
+        );
+    }
+
+    #[test]
+    fn hungarian_finds_the_cheapest_assignment() {
+        let cost = vec![
+            vec![4.0, 1.0, 3.0],
+            vec![2.0, 0.0, 5.0],
+            vec![3.0, 2.0, 2.0],
+        ];
+        assert_eq!(hungarian(&cost), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn shared_interests_are_preferred() {
+        // Only 1 and 2 share an interest, which a uniform draw pairs up about 1 time in 19
+        let participants = uids(20);
+        let mut rules = Rules::default();
+        rules.set_interests(1, ["Knitting "]);
+        rules.set_interests(2, ["knitting", "coffee"]);
+        let matched = (0..500)
+            .filter(|&seed| {
+                let assignment =
+                    draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(seed)).unwrap();
+                assignment.validate(&participants, &rules).unwrap();
+                assignment.pairs().contains(&Pair {
+                    giver: 1,
+                    receiver: 2,
+                })
+            })
+            .count();
+        // Sharing raises the odds, but doesn't give away who drew whom
+        assert!(
+            (40..150).contains(&matched),
+            "1 gave to 2 in {matched} draws"
+        );
+        // Interests only steer the draw, so exclusions still hold
+        rules.exclude(1, 2);
+        rules.set_gifts_per_person(2);
+        let assignment = draw(&participants, &rules, &mut ChaCha20Rng::seed_from_u64(0)).unwrap();
+        assignment.validate(&participants, &rules).unwrap();
+        assert!(!rules.ignores_interests(participants.len()));
+        assert!(rules.ignores_interests(PREFERENCE_LIMIT + 1));
+    }
+}
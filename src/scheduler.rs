@@ -0,0 +1,532 @@
+use async_sqlite::rusqlite::{
+    Connection, Result, params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef},
+};
+
+/// How long each round of an advent party lasts, in seconds
+pub const ROUND_LENGTH: i64 = 24 * 60 * 60;
+/// How long before the shipping deadline everyone is reminded of it, in seconds
+pub const SHIPPING_REMINDER_LEAD: i64 = 24 * 60 * 60;
+/// How often a job is tried before it is given up on and reported
+pub const MAX_ATTEMPTS: u32 = 5;
+/// How long to wait before retrying a failed job, doubled after every attempt
+const RETRY_DELAY: i64 = 60;
+
+/// What a scheduled job does when it falls due
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobKind {
+    /// Close signups and draw the party, or start its white elephant game
+    Draw,
+    /// Draw the next daily round of an advent party
+    NextRound,
+    /// Remind everyone that gifts have to ship soon
+    ShippingReminder,
+    /// Publish the draw's seed in the party's channel
+    Reveal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Pending,
+    Done,
+    /// Failed on every attempt
+    Failed,
+}
+
+impl JobKind {
+    const ALL: [JobKind; 4] = [
+        JobKind::Draw,
+        JobKind::NextRound,
+        JobKind::ShippingReminder,
+        JobKind::Reveal,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobKind::Draw => "draw",
+            JobKind::NextRound => "next round",
+            JobKind::ShippingReminder => "shipping reminder",
+            JobKind::Reveal => "reveal",
+        }
+    }
+}
+
+impl JobStatus {
+    const ALL: [JobStatus; 3] = [JobStatus::Pending, JobStatus::Done, JobStatus::Failed];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+impl std::fmt::Display for JobKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl ToSql for JobKind {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for JobKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let kind = value.as_str()?;
+        JobKind::ALL
+            .into_iter()
+            .find(|known| known.as_str() == kind)
+            .ok_or_else(|| FromSqlError::Other(format!("unknown job kind {kind}").into()))
+    }
+}
+
+impl ToSql for JobStatus {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for JobStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let status = value.as_str()?;
+        JobStatus::ALL
+            .into_iter()
+            .find(|known| known.as_str() == status)
+            .ok_or_else(|| FromSqlError::Other(format!("unknown job status {status}").into()))
+    }
+}
+
+/// One row of the `scheduled_jobs` table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub id: i64,
+    pub party_id: String,
+    pub kind: JobKind,
+    pub due_at: i64,
+    pub attempts: u32,
+    pub status: JobStatus,
+    pub last_error: Option<String>,
+}
+
+/// Every job a party needs over its life, and when each falls due
+pub fn party_jobs(
+    ends_at: i64,
+    rounds: u32,
+    ship_by: Option<i64>,
+    reveal_at: Option<i64>,
+) -> Vec<(JobKind, i64)> {
+    let mut jobs = vec![(JobKind::Draw, ends_at)];
+    for round in 1..i64::from(rounds) {
+        jobs.push((JobKind::NextRound, ends_at + round * ROUND_LENGTH));
+    }
+    if let Some(ship_by) = ship_by {
+        // Deadlines too close to the draw get their reminder right as they pass
+        let remind_at = if ship_by - SHIPPING_REMINDER_LEAD > ends_at {
+            ship_by - SHIPPING_REMINDER_LEAD
+        } else {
+            ship_by
+        };
+        jobs.push((JobKind::ShippingReminder, remind_at));
+    }
+    if let Some(reveal_at) = reveal_at {
+        jobs.push((JobKind::Reveal, reveal_at));
+    }
+    jobs
+}
+
+/// Adds a job, unless the same one is already scheduled
+pub fn enqueue(dbc: &Connection, party_id: &str, kind: JobKind, due_at: i64) -> Result<()> {
+    dbc.execute(
+        "INSERT OR IGNORE INTO scheduled_jobs (party_id, kind, due_at) VALUES (?1, ?2, ?3)",
+        params![party_id, kind, due_at],
+    )?;
+    Ok(())
+}
+
+/// Schedules the next round right away for a multi-round party whose first draw came after
+/// its second round was due. Rounds skipped while it waited are drawn by that job.
+pub fn catch_up_rounds(
+    dbc: &Connection,
+    party_id: &str,
+    ends_at: i64,
+    rounds: u32,
+    now: i64,
+) -> Result<()> {
+    if rounds > 1 && now >= ends_at + ROUND_LENGTH {
+        enqueue(dbc, party_id, JobKind::NextRound, now)?;
+    }
+    Ok(())
+}
+
+/// Schedules the jobs of parties from before the scheduler existed.
+/// Anything that should already have happened is left out.
+pub fn backfill(dbc: &Connection, now: i64) -> Result<()> {
+    let parties = dbc
+        .prepare(
+            "SELECT id, ends_at, rounds, ship_by, reveal_at FROM parties WHERE state IN ('open', 'drawn')",
+        )?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>("id")?,
+                party_jobs(
+                    row.get::<_, i64>("ends_at")?,
+                    row.get::<_, u32>("rounds")?,
+                    row.get::<_, Option<i64>>("ship_by")?,
+                    row.get::<_, Option<i64>>("reveal_at")?,
+                ),
+            ))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    for (party_id, jobs) in parties {
+        for (kind, due_at) in jobs {
+            if due_at > now {
+                enqueue(dbc, &party_id, kind, due_at)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finds the parties whose signups closed without being drawn, like those that ended while
+/// the bot was offline, and schedules an immediate draw for those without one planned. Closed
+/// parties that were never drawn are caught up too, since migrating a database from before the
+/// scheduler closes its overdue parties without drawing them. Those whose draw was given up on
+/// are left to their admin. Returns every overdue party, including those whose draw job was
+/// already due.
+pub fn catch_up(dbc: &Connection, now: i64) -> Result<Vec<String>> {
+    let overdue = dbc
+        .prepare(
+            "SELECT id, EXISTS (
+                SELECT 1 FROM scheduled_jobs
+                    WHERE party_id = parties.id AND kind = ?2 AND status = 'pending'
+            ) AS planned FROM parties WHERE ends_at <= ?1 AND (
+                state = 'open' OR (state = 'closed' AND NOT EXISTS (
+                    SELECT 1 FROM assignments WHERE party_id = parties.id
+                ) AND NOT EXISTS (
+                    SELECT 1 FROM scheduled_jobs
+                        WHERE party_id = parties.id AND kind = ?2 AND status = 'failed'
+                ))
+            ) AND NOT EXISTS (
+                SELECT 1 FROM scheduled_jobs
+                    WHERE party_id = parties.id AND kind = ?2 AND status = 'pending' AND due_at > ?1
+            ) ORDER BY id",
+        )?
+        .query_map(params![now, JobKind::Draw], |row| {
+            Ok((row.get::<_, String>("id")?, row.get::<_, bool>("planned")?))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    let mut caught_up = Vec::with_capacity(overdue.len());
+    for (party_id, planned) in overdue {
+        if !planned {
+            enqueue(dbc, &party_id, JobKind::Draw, now)?;
+        }
+        caught_up.push(party_id);
+    }
+    Ok(caught_up)
+}
+
+fn read_job(row: &async_sqlite::rusqlite::Row<'_>) -> Result<Job> {
+    Ok(Job {
+        id: row.get::<_, i64>("id")?,
+        party_id: row.get::<_, String>("party_id")?,
+        kind: row.get::<_, JobKind>("kind")?,
+        due_at: row.get::<_, i64>("due_at")?,
+        attempts: row.get::<_, u32>("attempts")?,
+        status: row.get::<_, JobStatus>("status")?,
+        last_error: row.get::<_, Option<String>>("last_error")?,
+    })
+}
+
+/// Pending jobs that are due at `now`, oldest first
+pub fn due(dbc: &Connection, now: i64) -> Result<Vec<Job>> {
+    dbc.prepare(
+        "SELECT * FROM scheduled_jobs WHERE status = 'pending' AND due_at <= ?1 ORDER BY due_at, id",
+    )?
+    .query_map([now], read_job)?
+    .collect()
+}
+
+/// When the next pending job falls due
+pub fn next_due(dbc: &Connection) -> Result<Option<i64>> {
+    dbc.query_one(
+        "SELECT MIN(due_at) FROM scheduled_jobs WHERE status = 'pending'",
+        [],
+        |row| row.get::<_, Option<i64>>(0),
+    )
+}
+
+/// Every job of a party, in the order they fall due
+pub fn list(dbc: &Connection, party_id: &str) -> Result<Vec<Job>> {
+    dbc.prepare("SELECT * FROM scheduled_jobs WHERE party_id = ?1 ORDER BY due_at, id")?
+        .query_map([party_id], read_job)?
+        .collect()
+}
+
+pub fn finish(dbc: &Connection, job_id: i64) -> Result<()> {
+    dbc.execute(
+        "UPDATE scheduled_jobs SET status = ?1, attempts = attempts + 1 WHERE id = ?2",
+        params![JobStatus::Done, job_id],
+    )?;
+    Ok(())
+}
+
+/// Records a failed attempt and pushes the job back, or gives up on it after
+/// [`MAX_ATTEMPTS`]. Returns whether it was given up on.
+pub fn fail(dbc: &Connection, job: &Job, error: &str, now: i64) -> Result<bool> {
+    let attempts = job.attempts + 1;
+    let given_up = attempts >= MAX_ATTEMPTS;
+    dbc.execute(
+        "UPDATE scheduled_jobs SET status = ?1, attempts = ?2, due_at = ?3, last_error = ?4 WHERE id = ?5",
+        params![
+            if given_up {
+                JobStatus::Failed
+            } else {
+                JobStatus::Pending
+            },
+            attempts,
+            now + (RETRY_DELAY << job.attempts),
+            error,
+            job.id
+        ],
+    )?;
+    Ok(given_up)
+}
+
+/// Gives up on a job straight away, for failures that retrying can't fix
+pub fn give_up(dbc: &Connection, job: &Job, error: &str) -> Result<()> {
+    dbc.execute(
+        "UPDATE scheduled_jobs SET status = ?1, attempts = attempts + 1, last_error = ?2 WHERE id = ?3",
+        params![JobStatus::Failed, error, job.id],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dbc() -> Connection {
+        let dbc = Connection::open_in_memory().unwrap();
+        crate::schema::migrate(&dbc).unwrap();
+        dbc
+    }
+
+    #[test]
+    fn shipping_reminders_go_out_a_day_before_the_deadline() {
+        let ends_at = 1000;
+        let ship_by = ends_at + 7 * SHIPPING_REMINDER_LEAD;
+        assert_eq!(
+            party_jobs(ends_at, 2, Some(ship_by), Some(ship_by + 1)),
+            vec![
+                (JobKind::Draw, ends_at),
+                (JobKind::NextRound, ends_at + ROUND_LENGTH),
+                (JobKind::ShippingReminder, ship_by - SHIPPING_REMINDER_LEAD),
+                (JobKind::Reveal, ship_by + 1),
+            ]
+        );
+        assert_eq!(
+            party_jobs(ends_at, 1, None, None),
+            vec![(JobKind::Draw, ends_at)]
+        );
+    }
+
+    #[test]
+    fn deadlines_close_to_the_draw_are_reminded_as_they_pass() {
+        let ends_at = 1000;
+        // A day early would be before anyone knows who they are gifting
+        let ship_by = ends_at + SHIPPING_REMINDER_LEAD / 2;
+        assert_eq!(
+            party_jobs(ends_at, 1, Some(ship_by), None),
+            vec![
+                (JobKind::Draw, ends_at),
+                (JobKind::ShippingReminder, ship_by)
+            ]
+        );
+        // Exactly a day after the draw would remind right as it happens
+        let ship_by = ends_at + SHIPPING_REMINDER_LEAD;
+        assert_eq!(
+            party_jobs(ends_at, 1, Some(ship_by), None)[1],
+            (JobKind::ShippingReminder, ship_by)
+        );
+    }
+
+    #[test]
+    fn jobs_come_due_in_order_and_only_once() {
+        let dbc = dbc();
+        enqueue(&dbc, "party", JobKind::Reveal, 300).unwrap();
+        enqueue(&dbc, "party", JobKind::Draw, 100).unwrap();
+        enqueue(&dbc, "party", JobKind::Draw, 100).unwrap();
+        assert_eq!(next_due(&dbc).unwrap(), Some(100));
+        assert!(due(&dbc, 99).unwrap().is_empty());
+        let jobs = due(&dbc, 300).unwrap();
+        assert_eq!(
+            jobs.iter().map(|job| job.kind).collect::<Vec<_>>(),
+            vec![JobKind::Draw, JobKind::Reveal]
+        );
+        finish(&dbc, jobs[0].id).unwrap();
+        assert_eq!(next_due(&dbc).unwrap(), Some(300));
+        assert_eq!(list(&dbc, "party").unwrap()[0].status, JobStatus::Done);
+    }
+
+    #[test]
+    fn failed_jobs_back_off_and_are_given_up_on() {
+        let dbc = dbc();
+        enqueue(&dbc, "party", JobKind::Draw, 0).unwrap();
+        let mut now = 0;
+        for attempt in 1..MAX_ATTEMPTS {
+            let job = due(&dbc, now).unwrap().remove(0);
+            assert!(!fail(&dbc, &job, "discord is down", now).unwrap());
+            let retry_at = next_due(&dbc).unwrap().unwrap();
+            assert_eq!(retry_at, now + (RETRY_DELAY << (attempt - 1)));
+            now = retry_at;
+        }
+        let job = due(&dbc, now).unwrap().remove(0);
+        assert!(fail(&dbc, &job, "discord is down", now).unwrap());
+        assert_eq!(next_due(&dbc).unwrap(), None);
+        let job = list(&dbc, "party").unwrap().remove(0);
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.attempts, MAX_ATTEMPTS);
+        assert_eq!(job.last_error.as_deref(), Some("discord is down"));
+    }
+
+    #[test]
+    fn draws_that_cant_be_made_are_given_up_on_at_once() {
+        let dbc = dbc();
+        enqueue(&dbc, "party", JobKind::Draw, 0).unwrap();
+        let job = due(&dbc, 0).unwrap().remove(0);
+        give_up(&dbc, &job, "no draw satisfies every rule").unwrap();
+        assert_eq!(next_due(&dbc).unwrap(), None);
+        let job = list(&dbc, "party").unwrap().remove(0);
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.attempts, 1);
+        assert_eq!(
+            job.last_error.as_deref(),
+            Some("no draw satisfies every rule")
+        );
+    }
+
+    #[test]
+    fn late_first_draws_catch_up_on_their_missed_rounds() {
+        let dbc = dbc();
+        catch_up_rounds(&dbc, "single", 100, 1, 100 + 3 * ROUND_LENGTH).unwrap();
+        catch_up_rounds(&dbc, "on-time", 100, 3, 100 + ROUND_LENGTH - 1).unwrap();
+        catch_up_rounds(&dbc, "late", 100, 3, 100 + 2 * ROUND_LENGTH).unwrap();
+        assert!(list(&dbc, "single").unwrap().is_empty());
+        assert!(list(&dbc, "on-time").unwrap().is_empty());
+        assert_eq!(
+            list(&dbc, "late")
+                .unwrap()
+                .into_iter()
+                .map(|job| (job.kind, job.due_at))
+                .collect::<Vec<_>>(),
+            vec![(JobKind::NextRound, 100 + 2 * ROUND_LENGTH)]
+        );
+    }
+
+    #[test]
+    fn parties_that_closed_unnoticed_are_drawn_right_away() {
+        let dbc = dbc();
+        dbc.execute_batch(
+            "INSERT INTO parties (id, admin_id, party_name, started_at, ends_at, state) VALUES
+                ('missed', 1, 'Missed', 0, 100, 'open'),
+                ('planned', 1, 'Planned', 0, 100, 'open'),
+                ('postponed', 1, 'Postponed', 0, 100, 'open'),
+                ('future', 1, 'Future', 0, 900, 'open'),
+                ('drawn', 1, 'Drawn', 0, 100, 'drawn');",
+        )
+        .unwrap();
+        enqueue(&dbc, "planned", JobKind::Draw, 100).unwrap();
+        enqueue(&dbc, "postponed", JobKind::Draw, 700).unwrap();
+        // Parties whose draw fell due while the bot was offline are overdue too
+        let overdue = vec!["missed".to_owned(), "planned".to_owned()];
+        assert_eq!(catch_up(&dbc, 500).unwrap(), overdue);
+        let draws = |party_id: &str| {
+            list(&dbc, party_id)
+                .unwrap()
+                .into_iter()
+                .map(|job| (job.kind, job.due_at))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draws("missed"), vec![(JobKind::Draw, 500)]);
+        assert_eq!(draws("planned"), vec![(JobKind::Draw, 100)]);
+        // Until the draws run, they are still overdue but aren't planned twice
+        assert_eq!(catch_up(&dbc, 600).unwrap(), overdue);
+        assert_eq!(draws("missed"), vec![(JobKind::Draw, 500)]);
+    }
+
+    #[test]
+    fn undrawn_parties_from_before_the_scheduler_are_drawn_after_migrating() {
+        let dbc = Connection::open_in_memory().unwrap();
+        dbc.execute_batch(
+            "CREATE TABLE party_info  (
+                id text not null,
+                admin_id integer not null,
+                party_name text not null,
+                started_at integer not null,
+                ends_at integer not null,
+                matches_made bool not null default true
+            );
+            INSERT INTO party_info (id, admin_id, party_name, started_at, ends_at) VALUES
+                ('drawn', 1, 'Drawn', 0, 0),
+                ('stuck', 1, 'Stuck', 0, 0),
+                ('given-up', 1, 'Given up', 0, 0);
+            CREATE TABLE \"drawn-matches\" (
+                giver_id integer,
+                receiver_id integer,
+                receiver_name text,
+                receiver_hint text
+            );
+            INSERT INTO \"drawn-matches\" VALUES (1, 2, 'Two', ''), (2, 1, 'One', '');",
+        )
+        .unwrap();
+        crate::schema::migrate(&dbc).unwrap();
+        enqueue(&dbc, "given-up", JobKind::Draw, 0).unwrap();
+        let job = due(&dbc, 0).unwrap().remove(0);
+        give_up(&dbc, &job, "no draw satisfies every rule").unwrap();
+        let now = chrono::Utc::now().timestamp();
+        assert_eq!(catch_up(&dbc, now).unwrap(), vec!["stuck".to_owned()]);
+        assert_eq!(
+            due(&dbc, now)
+                .unwrap()
+                .into_iter()
+                .map(|job| (job.party_id, job.kind))
+                .collect::<Vec<_>>(),
+            vec![("stuck".to_owned(), JobKind::Draw)]
+        );
+    }
+
+    #[test]
+    fn backfilling_skips_what_already_happened() {
+        let dbc = dbc();
+        dbc.execute(
+            "INSERT INTO parties (id, admin_id, party_name, started_at, ends_at, state, rounds, reveal_at)
+                VALUES ('advent', 1, 'Advent', 0, 100, 'drawn', 3, 500000)",
+            [],
+        )
+        .unwrap();
+        backfill(&dbc, 100 + ROUND_LENGTH).unwrap();
+        let jobs = list(&dbc, "advent")
+            .unwrap()
+            .into_iter()
+            .map(|job| (job.kind, job.due_at))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            jobs,
+            vec![
+                (JobKind::NextRound, 100 + 2 * ROUND_LENGTH),
+                (JobKind::Reveal, 500000)
+            ]
+        );
+    }
+}
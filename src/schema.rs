@@ -0,0 +1,536 @@
+use async_sqlite::rusqlite::{Connection, Result, Transaction, TransactionBehavior};
+
+use crate::{lifecycle::PartyState, scheduler};
+
+/// Creates the shared tables and adds any columns an older database is missing
+pub fn migrate(dbc: &Connection) -> Result<()> {
+    // Backfills only run when the column they fill is added, so a migration that stops
+    // part of the way must not leave that column behind
+    let tx = Transaction::new_unchecked(dbc, TransactionBehavior::Immediate)?;
+    upgrade(&tx)?;
+    tx.commit()
+}
+
+fn upgrade(dbc: &Connection) -> Result<()> {
+    let backfill_jobs = !table_exists(dbc, "scheduled_jobs")?;
+    if table_exists(dbc, "party_info")? {
+        dbc.execute("ALTER TABLE party_info RENAME TO parties", [])?;
+    }
+    dbc.execute_batch(
+        "CREATE TABLE IF NOT EXISTS parties (
+            id text not null,
+            admin_id integer not null,
+            party_name text not null,
+            started_at integer not null,
+            ends_at integer not null,
+            state text not null default 'open'
+        );
+        CREATE TABLE IF NOT EXISTS participants (
+            party_id text not null,
+            uid integer not null,
+            name text not null,
+            hint text not null,
+            team_id integer,
+            region text,
+            ships_abroad bool not null default false,
+            budget integer,
+            interests text,
+            unique (party_id, uid)
+        );
+        CREATE TABLE IF NOT EXISTS assignments (
+            party_id text not null,
+            giver_id integer not null,
+            receiver_id integer not null,
+            receiver_name text not null,
+            receiver_hint text not null,
+            loop_id integer not null default 0,
+            loop_position integer not null default 0,
+            receiver_team text,
+            unique (party_id, giver_id, receiver_id)
+        );
+        CREATE TABLE IF NOT EXISTS party_exclusions (
+            party_id text not null,
+            user_a integer not null,
+            user_b integer not null,
+            unique (party_id, user_a, user_b)
+        );
+        CREATE TABLE IF NOT EXISTS party_teams (
+            party_id text not null,
+            team_id integer not null,
+            team_name text not null,
+            unique (party_id, team_id),
+            unique (party_id, team_name)
+        );
+        CREATE TABLE IF NOT EXISTS party_pins (
+            party_id text not null,
+            giver_id integer not null,
+            receiver_id integer not null,
+            unique (party_id, giver_id, receiver_id)
+        );
+        CREATE TABLE IF NOT EXISTS party_admissions (
+            party_id text not null,
+            uid integer not null,
+            unique (party_id, uid)
+        );
+        CREATE TABLE IF NOT EXISTS party_redraws (
+            party_id text not null,
+            admin_id integer not null,
+            redrawn_at integer not null
+        );
+        CREATE TABLE IF NOT EXISTS party_rounds (
+            party_id text not null,
+            round integer not null,
+            giver_id integer not null,
+            receiver_id integer not null,
+            receiver_name text not null,
+            receiver_hint text not null,
+            receiver_team text
+        );
+        CREATE TABLE IF NOT EXISTS party_seals (
+            party_id text not null,
+            round integer not null,
+            seed text not null,
+            input text,
+            record text not null,
+            commitment text not null,
+            unique (party_id, round)
+        );
+        CREATE TABLE IF NOT EXISTS scheduled_jobs (
+            id integer primary key,
+            party_id text not null,
+            kind text not null,
+            due_at integer not null,
+            attempts integer not null default 0,
+            status text not null default 'pending',
+            last_error text,
+            unique (party_id, kind, due_at)
+        );
+        CREATE TABLE IF NOT EXISTS elephant_games (
+            party_id text primary key,
+            turn_order text not null,
+            turn integer not null,
+            up integer,
+            steal_limit integer not null,
+            last_stolen integer
+        );
+        CREATE TABLE IF NOT EXISTS elephant_gifts (
+            party_id text not null,
+            gift_id integer not null,
+            brought_by integer not null,
+            description text,
+            holder integer,
+            steals integer not null,
+            unique (party_id, gift_id)
+        );
+        -- Finding someone's parties and targets looks them up across every party
+        CREATE INDEX IF NOT EXISTS participants_uid ON participants (uid);
+        CREATE INDEX IF NOT EXISTS assignments_giver ON assignments (party_id, giver_id);",
+    )?;
+    add_column(dbc, "parties", "follows_id", "text")?;
+    add_column(
+        dbc,
+        "parties",
+        "history_depth",
+        "integer not null default 1",
+    )?;
+    add_column(dbc, "parties", "single_loop", "bool not null default false")?;
+    add_column(
+        dbc,
+        "parties",
+        "gifts_per_person",
+        "integer not null default 1",
+    )?;
+    add_column(dbc, "parties", "channel_id", "integer")?;
+    add_column(dbc, "parties", "regional", "bool not null default false")?;
+    add_column(
+        dbc,
+        "parties",
+        "budget_tiers",
+        "bool not null default false",
+    )?;
+    add_column(
+        dbc,
+        "parties",
+        "white_elephant",
+        "bool not null default false",
+    )?;
+    add_column(dbc, "parties", "steal_limit", "integer not null default 3")?;
+    add_column(dbc, "parties", "rounds", "integer not null default 1")?;
+    add_column(
+        dbc,
+        "parties",
+        "current_round",
+        "integer not null default 1",
+    )?;
+    add_column(
+        dbc,
+        "parties",
+        "min_participants",
+        "integer not null default 2",
+    )?;
+    add_column(dbc, "parties", "ship_by", "integer")?;
+    add_column(dbc, "parties", "reveal_at", "integer")?;
+    // Parties from before the state column get theirs from what they left behind
+    let backfill_state = add_column(dbc, "parties", "state", "text not null default 'open'")?;
+    if backfill_state {
+        dbc.execute(
+            "UPDATE parties SET state = ?1 WHERE ends_at <= ?2",
+            (PartyState::Closed, chrono::Utc::now().timestamp()),
+        )?;
+        if column_exists(dbc, "parties", "cancelled")? {
+            dbc.execute(
+                "UPDATE parties SET state = ?1 WHERE cancelled",
+                [PartyState::Cancelled],
+            )?;
+        }
+        dbc.execute(
+            "UPDATE parties SET state = CASE WHEN up IS NULL THEN ?1 ELSE ?2 END
+                FROM elephant_games WHERE elephant_games.party_id = parties.id",
+            (PartyState::Archived, PartyState::Drawn),
+        )?;
+    }
+    // Parties revealed before this was tracked published their seals then
+    if add_column(
+        dbc,
+        "parties",
+        "seals_published",
+        "bool not null default false",
+    )? {
+        dbc.execute(
+            "UPDATE parties SET seals_published = true WHERE state IN (?1, ?2)",
+            (PartyState::Revealed, PartyState::Archived),
+        )?;
+    }
+    drop_column(dbc, "parties", "matches_made")?;
+    drop_column(dbc, "parties", "cancelled")?;
+    // Seals used to live on the party, which only had room for its latest round. The seed is
+    // kept as text, since it uses the full range of a u64.
+    if column_exists(dbc, "parties", "draw_seed")? {
+        add_column(dbc, "parties", "draw_input", "text")?;
+        dbc.execute(
+            "INSERT OR IGNORE INTO party_seals (party_id, round, seed, input, record, commitment)
+                SELECT id, current_round, draw_seed, draw_input, draw_record, draw_commitment
+                    FROM parties WHERE draw_seed IS NOT NULL
+                        AND draw_record IS NOT NULL AND draw_commitment IS NOT NULL",
+            [],
+        )?;
+        for column in ["draw_seed", "draw_input", "draw_record", "draw_commitment"] {
+            drop_column(dbc, "parties", column)?;
+        }
+    }
+    // Older parties kept their signups and matches in tables of their own, which are moved
+    // into the shared ones
+    let party_ids = dbc
+        .prepare("SELECT id FROM parties")?
+        .query_map([], |row| row.get::<_, String>("id"))?
+        .collect::<Result<Vec<_>>>()?;
+    for party_id in party_ids {
+        if table_exists(dbc, &party_id)? {
+            add_column(dbc, &party_id, "team_id", "integer")?;
+            add_column(dbc, &party_id, "region", "text")?;
+            add_column(
+                dbc,
+                &party_id,
+                "ships_abroad",
+                "bool not null default false",
+            )?;
+            add_column(dbc, &party_id, "budget", "integer")?;
+            add_column(dbc, &party_id, "interests", "text")?;
+            dbc.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO participants
+                        (party_id, uid, name, hint, team_id, region, ships_abroad, budget, interests)
+                    SELECT ?1, CAST(uid AS INTEGER), name, hint, team_id, region, ships_abroad, budget, interests
+                        FROM \"{party_id}\""
+                ),
+                [&party_id],
+            )?;
+            dbc.execute(&format!("DROP TABLE \"{party_id}\""), [])?;
+        }
+        let matches = format!("{party_id}-matches");
+        if table_exists(dbc, &matches)? {
+            add_column(dbc, &matches, "loop_id", "integer not null default 0")?;
+            add_column(dbc, &matches, "loop_position", "integer not null default 0")?;
+            add_column(dbc, &matches, "receiver_team", "text")?;
+            // A draw that failed or crashed part of the way left an empty or partial table
+            // behind. Its rows are dropped, so the party stays closed and gets drawn again.
+            let complete = dbc.query_one(
+                &format!(
+                    "SELECT EXISTS (SELECT 1 FROM \"{matches}\") AND NOT EXISTS (
+                        SELECT 1 FROM participants WHERE party_id = ?1
+                            AND uid NOT IN (SELECT giver_id FROM \"{matches}\")
+                    )"
+                ),
+                [&party_id],
+                |row| row.get::<_, bool>(0),
+            )?;
+            if complete && backfill_state {
+                dbc.execute(
+                    "UPDATE parties SET state = ?1 WHERE id = ?2",
+                    (PartyState::Drawn, &party_id),
+                )?;
+            }
+            if complete {
+                dbc.execute(
+                    &format!(
+                        "INSERT OR IGNORE INTO assignments
+                            (party_id, giver_id, receiver_id, receiver_name, receiver_hint, loop_id, loop_position, receiver_team)
+                        SELECT ?1, giver_id, receiver_id, receiver_name, receiver_hint, loop_id, loop_position, receiver_team
+                            FROM \"{matches}\" ORDER BY rowid"
+                    ),
+                    [&party_id],
+                )?;
+            }
+            dbc.execute(&format!("DROP TABLE \"{matches}\""), [])?;
+        }
+    }
+    // Parties from before the scheduler still need their timers
+    if backfill_jobs {
+        scheduler::backfill(dbc, chrono::Utc::now().timestamp())?;
+    }
+    Ok(())
+}
+
+fn table_exists(dbc: &Connection, table: &str) -> Result<bool> {
+    dbc.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1")?
+        .exists([table])
+}
+
+fn column_exists(dbc: &Connection, table: &str, column: &str) -> Result<bool> {
+    dbc.prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+        .exists([table, column])
+}
+
+/// Returns whether the column was missing and had to be added
+fn add_column(dbc: &Connection, table: &str, column: &str, definition: &str) -> Result<bool> {
+    if column_exists(dbc, table, column)? {
+        return Ok(false);
+    }
+    dbc.execute(
+        &format!("ALTER TABLE \"{table}\" ADD COLUMN {column} {definition}"),
+        [],
+    )?;
+    Ok(true)
+}
+
+fn drop_column(dbc: &Connection, table: &str, column: &str) -> Result<()> {
+    if column_exists(dbc, table, column)? {
+        dbc.execute(&format!("ALTER TABLE \"{table}\" DROP COLUMN {column}"), [])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrating_twice_is_harmless() {
+        let dbc = Connection::open_in_memory().unwrap();
+        dbc.execute_batch(
+            "CREATE TABLE party_info  (
+                id text not null,
+                admin_id integer not null,
+                party_name text not null,
+                started_at integer not null,
+                ends_at integer not null,
+                matches_made bool not null default true
+            );
+            INSERT INTO party_info (id, admin_id, party_name, started_at, ends_at)
+                VALUES ('old', 1, 'Old party', 0, 0);",
+        )
+        .unwrap();
+        migrate(&dbc).unwrap();
+        migrate(&dbc).unwrap();
+        let depth: i64 = dbc
+            .query_one(
+                "SELECT history_depth FROM parties WHERE id = 'old'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(depth, 1);
+        let indexes = dbc
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'index' AND name NOT LIKE 'sqlite_%' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(indexes, vec!["assignments_giver", "participants_uid"]);
+    }
+
+    #[test]
+    fn failed_migrations_leave_nothing_behind() {
+        let dbc = Connection::open_in_memory().unwrap();
+        dbc.execute_batch(
+            "CREATE TABLE party_info  (
+                id text not null,
+                admin_id integer not null,
+                party_name text not null,
+                started_at integer not null,
+                ends_at integer not null
+            );
+            INSERT INTO party_info (id, admin_id, party_name, started_at, ends_at)
+                VALUES ('old', 1, 'Old party', 0, 0);
+            CREATE TABLE \"old-matches\" (giver_id integer not null, receiver_id integer not null);
+            INSERT INTO \"old-matches\" VALUES (7, 8), (8, 7);",
+        )
+        .unwrap();
+        assert!(migrate(&dbc).is_err());
+        assert!(table_exists(&dbc, "party_info").unwrap());
+        assert!(!table_exists(&dbc, "parties").unwrap());
+        assert!(!column_exists(&dbc, "party_info", "state").unwrap());
+        assert!(table_exists(&dbc, "old-matches").unwrap());
+    }
+
+    #[test]
+    fn old_parties_get_a_state_from_what_they_left_behind() {
+        let dbc = Connection::open_in_memory().unwrap();
+        dbc.execute_batch(
+            "CREATE TABLE party_info  (
+                id text not null,
+                admin_id integer not null,
+                party_name text not null,
+                started_at integer not null,
+                ends_at integer not null,
+                matches_made bool not null default true
+            );
+            INSERT INTO party_info (id, admin_id, party_name, started_at, ends_at) VALUES
+                ('drawn', 1, 'Drawn', 0, 0),
+                ('failed', 1, 'Failed', 0, 0),
+                ('crashed', 1, 'Crashed', 0, 0),
+                ('stuck', 1, 'Stuck', 0, 0),
+                ('open', 1, 'Open', 0, 9999999999);",
+        )
+        .unwrap();
+        for (party_id, matches) in [
+            ("drawn", "(7, 8, 'c', 'd'), (8, 7, 'a', 'b')"),
+            ("failed", ""),
+            ("crashed", "(7, 8, 'c', 'd')"),
+        ] {
+            dbc.execute_batch(&format!(
+                "CREATE TABLE \"{party_id}\" (uid TEXT NOT NULL UNIQUE, name TEXT NOT NULL, hint TEXT NOT NULL);
+                INSERT INTO \"{party_id}\" VALUES ('7', 'a', 'b'), ('8', 'c', 'd');
+                CREATE TABLE \"{party_id}-matches\" (
+                    giver_id integer,
+                    receiver_id integer,
+                    receiver_name text,
+                    receiver_hint text
+                );"
+            ))
+            .unwrap();
+            if !matches.is_empty() {
+                dbc.execute_batch(&format!(
+                    "INSERT INTO \"{party_id}-matches\" VALUES {matches};"
+                ))
+                .unwrap();
+            }
+        }
+        migrate(&dbc).unwrap();
+        let state = |id: &str| -> PartyState {
+            dbc.query_one("SELECT state FROM parties WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(state("drawn"), PartyState::Drawn);
+        // Only a draw that gave every participant a target counts
+        assert_eq!(state("failed"), PartyState::Closed);
+        assert_eq!(state("crashed"), PartyState::Closed);
+        let left_behind: i64 = dbc
+            .query_one(
+                "SELECT COUNT(*) FROM assignments WHERE party_id = 'crashed'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(left_behind, 0);
+        assert_eq!(state("stuck"), PartyState::Closed);
+        assert_eq!(state("open"), PartyState::Open);
+        assert!(!column_exists(&dbc, "parties", "matches_made").unwrap());
+    }
+
+    #[test]
+    fn seals_move_off_the_party() {
+        let dbc = Connection::open_in_memory().unwrap();
+        migrate(&dbc).unwrap();
+        dbc.execute_batch(
+            "ALTER TABLE parties ADD COLUMN draw_seed text;
+            ALTER TABLE parties ADD COLUMN draw_record text;
+            ALTER TABLE parties ADD COLUMN draw_commitment text;
+            INSERT INTO parties
+                (id, admin_id, party_name, started_at, ends_at, current_round, draw_seed, draw_record, draw_commitment)
+                VALUES ('advent', 1, 'Advent', 0, 0, 3, '18446744073709551615', '1>2;2>1', 'abc');",
+        )
+        .unwrap();
+        migrate(&dbc).unwrap();
+        migrate(&dbc).unwrap();
+        assert!(!column_exists(&dbc, "parties", "draw_seed").unwrap());
+        let seal: (u32, String, Option<String>, String) = dbc
+            .query_one(
+                "SELECT round, seed, input, record FROM party_seals WHERE party_id = 'advent'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(seal, (3, u64::MAX.to_string(), None, "1>2;2>1".to_owned()));
+    }
+
+    #[test]
+    fn per_party_tables_move_into_the_shared_ones() {
+        let dbc = Connection::open_in_memory().unwrap();
+        dbc.execute_batch(
+            "CREATE TABLE party_info  (
+                id text not null,
+                admin_id integer not null,
+                party_name text not null,
+                started_at integer not null,
+                ends_at integer not null
+            );
+            INSERT INTO party_info (id, admin_id, party_name, started_at, ends_at)
+                VALUES ('old', 1, 'Old party', 0, 0);
+            CREATE TABLE \"old\" (
+                uid TEXT NOT NULL UNIQUE,
+                name TEXT NOT NULL,
+                hint TEXT NOT NULL
+            );
+            INSERT INTO \"old\" (uid, name, hint) VALUES ('7', 'a', 'b'), ('8', 'c', 'd');
+            CREATE TABLE \"old-matches\" (
+                giver_id integer not null,
+                receiver_id integer not null,
+                receiver_name text not null,
+                receiver_hint text not null
+            );
+            INSERT INTO \"old-matches\" VALUES (7, 8, 'c', 'd'), (8, 7, 'a', 'b');",
+        )
+        .unwrap();
+        migrate(&dbc).unwrap();
+        migrate(&dbc).unwrap();
+        assert!(!table_exists(&dbc, "party_info").unwrap());
+        assert!(!table_exists(&dbc, "old").unwrap());
+        assert!(!table_exists(&dbc, "old-matches").unwrap());
+        let participants = dbc
+            .prepare("SELECT uid FROM participants WHERE party_id = 'old' ORDER BY uid")
+            .unwrap()
+            .query_map([], |row| row.get::<_, u64>(0))
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(participants, vec![7, 8]);
+        let receiver: String = dbc
+            .query_one(
+                "SELECT receiver_name FROM assignments WHERE party_id = 'old' AND giver_id = 8",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(receiver, "a");
+        let state: PartyState = dbc
+            .query_one("SELECT state FROM parties WHERE id = 'old'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(state, PartyState::Drawn);
+    }
+}